@@ -0,0 +1,374 @@
+//! Generates `LAYERS` and the `Layer` enum from keymap.toml, the output is
+//! included by src/keymap.rs.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use toml::Value;
+
+const KEYMAP: &str = "keymap.toml";
+
+struct LayerDef {
+    name: String,
+    label: String,
+    keys: Vec<Vec<String>>,
+    // clockwise and counter-clockwise, left then right
+    encoders: [String; 4],
+    colour: [u8; 3],
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", KEYMAP);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let source = fs::read_to_string(KEYMAP)
+        .unwrap_or_else(|e| fail(format!("can't be read: {}", e)));
+    let generated = generate(&source).unwrap_or_else(|e| fail(e));
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("layers.rs");
+    fs::write(out, generated).unwrap();
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}: {}", KEYMAP, message);
+    process::exit(1);
+}
+
+fn generate(source: &str) -> Result<String, String> {
+    let keymap: Value = source.parse().map_err(|e| format!("{}", e))?;
+    let rows = size(&keymap, "rows")?;
+    let columns = size(&keymap, "columns")?;
+    let aliases = aliases(&keymap)?;
+
+    let layers = keymap
+        .get("layers")
+        .and_then(Value::as_array)
+        .ok_or("no [[layers]]")?
+        .iter()
+        .enumerate()
+        .map(|(i, l)| layer(l, i, rows, columns))
+        .collect::<Result<Vec<_>, _>>()?;
+    // the first layer is the default one
+    if layers.is_empty() {
+        return Err("no [[layers]]".into());
+    }
+
+    let names: Vec<&str> = layers.iter().map(|l| l.name.as_str()).collect();
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return Err(format!("layer `{}` is defined twice", name));
+        }
+        if *name == "Missing" {
+            return Err("`Missing` is reserved for layers out of range".into());
+        }
+    }
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by build.rs from {}, edit that instead.", KEYMAP).unwrap();
+    writeln!(out).unwrap();
+    layer_enum(&mut out, &layers);
+
+    writeln!(out, "pub const LAYER_COUNT: usize = {};", layers.len()).unwrap();
+    writeln!(out, "pub const ROWS: usize = {};", rows).unwrap();
+    writeln!(out, "pub const COLUMNS: usize = {};", columns).unwrap();
+    let colours: Vec<String> = layers
+        .iter()
+        .map(|l| format!("({}, {}, {})", l.colour[0], l.colour[1], l.colour[2]))
+        .collect();
+    writeln!(out, "// Lights the keys bound on a layer while it is held.").unwrap();
+    writeln!(
+        out,
+        "pub const LAYER_COLOURS: [(u8, u8, u8); LAYER_COUNT] = [{}];",
+        colours.join(", ")
+    )
+    .unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "// No switch is wired to this row, it holds layers for `LAYER_RULES`.").unwrap();
+    writeln!(out, "pub const LAYER_ROW: u8 = {};", rows).unwrap();
+    let layer_keys: Vec<String> = (0..layers.len()).map(|i| format!("l({})", i)).collect();
+    writeln!(
+        out,
+        "const LAYER_KEYS: &[Action<PkbAction>] = &[{}];",
+        layer_keys.join(", ")
+    )
+    .unwrap();
+    writeln!(out, "// Nor to this one, turning an encoder presses its keys.").unwrap();
+    writeln!(out, "pub const ENCODER_ROW: u8 = {};", rows + 1).unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "#[rustfmt::skip]").unwrap();
+    writeln!(out, "pub static LAYERS: keyberon::layout::Layers<PkbAction> = &[").unwrap();
+    for layer in layers.iter() {
+        writeln!(out, "    // {}", layer.name).unwrap();
+        writeln!(out, "    &[").unwrap();
+        for (i, row) in layer.keys.iter().enumerate() {
+            let keys = row
+                .iter()
+                .enumerate()
+                .map(|(j, key)| {
+                    expand(key, &aliases, &names).map_err(|e| {
+                        format!("layer `{}`, row {}, column {}: {}", layer.name, i, j, e)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            writeln!(out, "        &[{}],", keys.join(", ")).unwrap();
+        }
+        writeln!(out, "        LAYER_KEYS,").unwrap();
+        let encoders = layer
+            .encoders
+            .iter()
+            .map(|key| {
+                expand(key, &aliases, &names)
+                    .map_err(|e| format!("layer `{}`, encoders: {}", layer.name, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        writeln!(out, "        &[{}],", encoders.join(", ")).unwrap();
+        writeln!(out, "    ],").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    Ok(out)
+}
+
+fn size(keymap: &Value, key: &str) -> Result<usize, String> {
+    match keymap.get(key).and_then(Value::as_integer) {
+        Some(n) if n > 0 => Ok(n as usize),
+        _ => Err(format!("`{}` must be a positive number", key)),
+    }
+}
+
+fn aliases(keymap: &Value) -> Result<HashMap<String, String>, String> {
+    let table = match keymap.get("aliases") {
+        Some(aliases) => aliases.as_table().ok_or("[aliases] must be a table")?,
+        None => return Ok(HashMap::new()),
+    };
+    table
+        .iter()
+        .map(|(name, value)| match value.as_str() {
+            Some(expr) => Ok((name.clone(), expr.to_string())),
+            None => Err(format!("alias `{}` must be a string", name)),
+        })
+        .collect()
+}
+
+fn layer(value: &Value, index: usize, rows: usize, columns: usize) -> Result<LayerDef, String> {
+    let name = value
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or("a layer has no name")?
+        .to_string();
+    if !is_identifier(&name) {
+        return Err(format!("layer name `{}` isn't a Rust identifier", name));
+    }
+    let label = match value.get("label") {
+        Some(label) => label
+            .as_str()
+            .ok_or_else(|| format!("layer `{}`: label must be a string", name))?
+            .to_string(),
+        None => name.to_lowercase(),
+    };
+
+    let keys = value
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("layer `{}` has no keys", name))?;
+    if keys.len() != rows {
+        return Err(format!(
+            "layer `{}` has {} rows, expected {}",
+            name,
+            keys.len(),
+            rows
+        ));
+    }
+
+    let keys = keys
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let row = row
+                .as_array()
+                .ok_or_else(|| format!("layer `{}`, row {}: must be a list of keys", name, i))?;
+            if row.len() != columns {
+                return Err(format!(
+                    "layer `{}`, row {}: {} keys, expected {}",
+                    name,
+                    i,
+                    row.len(),
+                    columns
+                ));
+            }
+            row.iter()
+                .enumerate()
+                .map(|(j, key)| match key.as_str() {
+                    Some(key) => Ok(key.to_string()),
+                    None => Err(format!(
+                        "layer `{}`, row {}, column {}: keys must be strings",
+                        name, i, j
+                    )),
+                })
+                .collect()
+        })
+        .collect::<Result<_, _>>()?;
+
+    // layers above the default one turn it the same way unless told otherwise
+    let unbound = if index == 0 { "NoOp" } else { "Trans" };
+    let encoders = match value.get("encoders") {
+        Some(encoders) => encoders_of(encoders).ok_or_else(|| {
+            format!(
+                "layer `{}`: encoders must be [[clockwise, counter-clockwise], [..]], \
+                 left then right",
+                name
+            )
+        })?,
+        None => [unbound, unbound, unbound, unbound].map(String::from),
+    };
+
+    let colour = match value.get("colour") {
+        Some(colour) => colour_of(colour)
+            .ok_or_else(|| format!("layer `{}`: colour must be [red, green, blue], 0 to 255", name))?,
+        None => [0x80, 0x80, 0x80],
+    };
+
+    Ok(LayerDef {
+        name,
+        label,
+        keys,
+        encoders,
+        colour,
+    })
+}
+
+fn colour_of(value: &Value) -> Option<[u8; 3]> {
+    let rgb = value
+        .as_array()?
+        .iter()
+        .map(|c| c.as_integer().filter(|c| (0..=255).contains(c)).map(|c| c as u8))
+        .collect::<Option<Vec<_>>>()?;
+    match rgb.as_slice() {
+        [r, g, b] => Some([*r, *g, *b]),
+        _ => None,
+    }
+}
+
+fn encoders_of(value: &Value) -> Option<[String; 4]> {
+    let keys = value
+        .as_array()?
+        .iter()
+        .map(|e| match e.as_array().map(|e| e.as_slice()) {
+            Some([cw, ccw]) => Some([cw.as_str()?, ccw.as_str()?]),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match keys.as_slice() {
+        [[l_cw, l_ccw], [r_cw, r_ccw]] => Some([*l_cw, *l_ccw, *r_cw, *r_ccw].map(String::from)),
+        _ => None,
+    }
+}
+
+fn layer_enum(out: &mut String, layers: &[LayerDef]) {
+    writeln!(out, "#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]").unwrap();
+    writeln!(out, "pub enum Layer {{").unwrap();
+    for layer in layers.iter() {
+        writeln!(out, "    {},", layer.name).unwrap();
+    }
+    writeln!(out, "    Missing,").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "impl Default for Layer {{").unwrap();
+    writeln!(out, "    fn default() -> Self {{").unwrap();
+    writeln!(out, "        Layer::{}", layers[0].name).unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "impl From<usize> for Layer {{").unwrap();
+    writeln!(out, "    fn from(i: usize) -> Layer {{").unwrap();
+    writeln!(out, "        match i {{").unwrap();
+    for (i, layer) in layers.iter().enumerate() {
+        writeln!(out, "            {} => Layer::{},", i, layer.name).unwrap();
+    }
+    writeln!(out, "            _ => Layer::Missing,").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    // `Missing` is one past the last layer, which `From<usize>` maps back
+    writeln!(out, "impl From<Layer> for usize {{").unwrap();
+    writeln!(out, "    fn from(layer: Layer) -> Self {{").unwrap();
+    writeln!(out, "        layer as usize").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "impl From<Layer> for &str {{").unwrap();
+    writeln!(out, "    fn from(layer: Layer) -> &'static str {{").unwrap();
+    writeln!(out, "        match layer {{").unwrap();
+    for layer in layers.iter() {
+        writeln!(out, "            Layer::{} => {:?},", layer.name, layer.label).unwrap();
+    }
+    writeln!(out, "            Layer::Missing => \"missing\",").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+}
+
+fn expand(key: &str, aliases: &HashMap<String, String>, layers: &[&str]) -> Result<String, String> {
+    if let Some(expr) = aliases.get(key) {
+        return Ok(expr.clone());
+    }
+    if key == "Trans" || key == "NoOp" {
+        return Ok(key.to_string());
+    }
+
+    for f in ["l", "d"].iter() {
+        let arg = key
+            .strip_prefix(f)
+            .and_then(|k| k.strip_prefix('('))
+            .and_then(|k| k.strip_suffix(')'));
+        if let Some(name) = arg.filter(|a| is_identifier(a)) {
+            return if layers.contains(&name) {
+                Ok(format!("{}(Layer::{} as usize)", f, name))
+            } else {
+                Err(format!("unknown layer `{}`", name))
+            };
+        }
+    }
+
+    if is_key_code(key) {
+        Ok(format!("k({})", key))
+    } else if key.is_empty() {
+        Err("empty key, use `NoOp`".into())
+    } else {
+        Ok(key.to_string())
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+// Key codes are camel case, `Tab`, `Kb1`, `F12` or `A`, constants are
+// upper snake case.
+fn is_key_code(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_uppercase() => (),
+        _ => return false,
+    }
+    let rest = chars.as_str();
+    rest.chars().all(|c| c.is_ascii_alphanumeric())
+        && (rest.is_empty() || rest.chars().any(|c| c.is_ascii_lowercase() || c.is_ascii_digit()))
+}
@@ -0,0 +1,63 @@
+use keyberon::key_code::KeyCode;
+
+use crate::keyboard::KbHidReport;
+
+/// Shifts letters, and `-` into `_`, until a key that can't be part of a
+/// word is pressed or nothing is typed for `timeout` milliseconds.
+pub struct CapsWord {
+    active: bool,
+    last_active: u32,
+    timeout: u16,
+}
+
+impl CapsWord {
+    pub fn new(timeout: u16) -> Self {
+        CapsWord {
+            active: false,
+            last_active: 0,
+            timeout,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn toggle(&mut self, now: u32) {
+        self.active = !self.active;
+        self.last_active = now;
+    }
+
+    pub fn tick(&mut self, now: u32) {
+        if self.active && now.wrapping_sub(self.last_active) > self.timeout as u32 {
+            self.active = false;
+        }
+    }
+
+    /// The word ends before the report with the breaking key goes out, so
+    /// that key is never shifted.
+    pub fn modify_report(&mut self, now: u32, report: &mut KbHidReport) {
+        if !self.active {
+            return;
+        }
+
+        let mut shift = false;
+        for key in report.keys() {
+            match key {
+                k if (KeyCode::A as u8..=KeyCode::Z as u8).contains(&k) => shift = true,
+                k if k == KeyCode::Minus as u8 => shift = true,
+                k if (KeyCode::Kb1 as u8..=KeyCode::Kb0 as u8).contains(&k) => (),
+                k if k == KeyCode::BSpace as u8 || k == KeyCode::Delete as u8 => (),
+                _ => {
+                    self.active = false;
+                    return;
+                }
+            }
+            self.last_active = now;
+        }
+
+        if shift {
+            report.pressed(KeyCode::LShift);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use heapless::{
+    consts::{U16, U4},
+    Vec,
+};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+
+pub type Outputs<L> = Vec<Output<L>, U16>;
+
+/// `L` is the keymap's layer, see `keymap::COMBOS`.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ComboAction<L> {
+    Key(KeyCode),
+    ToggleLayer(L),
+}
+
+/// Keys are matrix coordinates as the layout sees them, so the right
+/// half uses its mirrored columns.
+#[derive(PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Combo<L: 'static> {
+    pub keys: &'static [(u8, u8)],
+    pub action: ComboAction<L>,
+    pub layers: &'static [L],
+    pub timeout: u16,
+}
+
+impl<L: PartialEq> Combo<L> {
+    fn contains(&self, coord: (u8, u8)) -> bool {
+        self.keys.contains(&coord)
+    }
+
+    fn is_enabled(&self, layer: L) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layer)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Output<L: 'static> {
+    Event(Event),
+    Press(&'static Combo<L>),
+    Release(&'static Combo<L>),
+}
+
+struct Active<L: 'static> {
+    combo: &'static Combo<L>,
+    held: Vec<(u8, u8), U4>,
+    released: bool,
+}
+
+/// Holds back presses of keys that are part of a combo until either all
+/// the keys of a combo are down or its timeout runs out. A combo is
+/// released as soon as any of its keys is, the releases of the other
+/// keys are swallowed.
+pub struct Combos<L: 'static> {
+    combos: &'static [Combo<L>],
+    waiting: Vec<((u8, u8), u32), U4>,
+    active: Vec<Active<L>, U4>,
+}
+
+impl<L: Copy + PartialEq> Combos<L> {
+    pub fn new(combos: &'static [Combo<L>]) -> Self {
+        Combos {
+            combos,
+            waiting: Vec::new(),
+            active: Vec::new(),
+        }
+    }
+
+    pub fn event(&mut self, now: u32, event: Event, layer: L) -> Outputs<L> {
+        let mut out = Outputs::new();
+        match event {
+            Event::Press(i, j) => self.press(now, (i, j), layer, &mut out),
+            Event::Release(i, j) => self.release((i, j), &mut out),
+        }
+        out
+    }
+
+    pub fn tick(&mut self, now: u32, layer: L) -> Outputs<L> {
+        let mut out = Outputs::new();
+        if let Some((_, since)) = self.waiting.first() {
+            let timeout = self
+                .candidates(layer)
+                .map(|c| c.timeout)
+                .min()
+                .unwrap_or(0);
+            if now.wrapping_sub(*since) >= timeout as u32 {
+                self.flush(&mut out);
+            }
+        }
+        out
+    }
+
+    fn candidates<'a>(&'a self, layer: L) -> impl Iterator<Item = &'static Combo<L>> + 'a {
+        let (combos, waiting) = (self.combos, &self.waiting);
+        combos.iter().filter(move |c| {
+            c.is_enabled(layer) && waiting.iter().all(|(coord, _)| c.contains(*coord))
+        })
+    }
+
+    fn press(&mut self, now: u32, coord: (u8, u8), layer: L, out: &mut Outputs<L>) {
+        let combos = self.combos;
+        let is_combo_key = combos
+            .iter()
+            .any(|c| c.is_enabled(layer) && c.contains(coord));
+
+        if !is_combo_key || self.waiting.push((coord, now)).is_err() {
+            self.flush(out);
+            out.push(Output::Event(Event::Press(coord.0, coord.1))).ok();
+            return;
+        }
+
+        // the oldest keys go out as they are until the ones left could
+        // still be a combo, the key just pressed may start another one
+        while self.candidates(layer).next().is_none() {
+            self.waiting.rotate_left(1);
+            match self.waiting.pop() {
+                Some((coord, _)) => {
+                    out.push(Output::Event(Event::Press(coord.0, coord.1))).ok();
+                }
+                None => return,
+            }
+        }
+
+        let waiting = &self.waiting;
+        let complete = self.candidates(layer).find(|c| {
+            c.keys.len() == waiting.len()
+                && c.keys.iter().all(|k| waiting.iter().any(|(w, _)| w == k))
+        });
+
+        if let Some(combo) = complete {
+            let mut held = Vec::new();
+            for (coord, _) in self.waiting.iter() {
+                held.push(*coord).ok();
+            }
+            self.waiting.clear();
+            if self
+                .active
+                .push(Active {
+                    combo,
+                    held,
+                    released: false,
+                })
+                .is_ok()
+            {
+                out.push(Output::Press(combo)).ok();
+            }
+        }
+    }
+
+    fn release(&mut self, coord: (u8, u8), out: &mut Outputs<L>) {
+        if self.waiting.iter().any(|(c, _)| *c == coord) {
+            self.flush(out);
+            out.push(Output::Event(Event::Release(coord.0, coord.1))).ok();
+            return;
+        }
+
+        match self.active.iter().position(|a| a.held.contains(&coord)) {
+            Some(i) => {
+                let active = &mut self.active[i];
+                if !active.released {
+                    active.released = true;
+                    out.push(Output::Release(active.combo)).ok();
+                }
+                if let Some(k) = active.held.iter().position(|c| *c == coord) {
+                    active.held.swap_remove(k);
+                }
+                if active.held.is_empty() {
+                    self.active.swap_remove(i);
+                }
+            }
+            None => {
+                out.push(Output::Event(Event::Release(coord.0, coord.1))).ok();
+            }
+        }
+    }
+
+    fn flush(&mut self, out: &mut Outputs<L>) {
+        for (coord, _) in self.waiting.iter() {
+            out.push(Output::Event(Event::Press(coord.0, coord.1))).ok();
+        }
+        self.waiting.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Press, Release};
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    enum Layer {
+        Base,
+        Other,
+    }
+
+    static COMBOS: &[Combo<Layer>] = &[
+        Combo {
+            keys: &[(1, 3), (1, 4)],
+            action: ComboAction::Key(KeyCode::Escape),
+            layers: &[],
+            timeout: 40,
+        },
+        Combo {
+            keys: &[(1, 4), (1, 5)],
+            action: ComboAction::Key(KeyCode::Tab),
+            layers: &[],
+            timeout: 40,
+        },
+        Combo {
+            keys: &[(2, 0), (2, 1)],
+            action: ComboAction::ToggleLayer(Layer::Base),
+            layers: &[Layer::Other],
+            timeout: 40,
+        },
+    ];
+
+    fn event(combos: &mut Combos<Layer>, now: u32, event: Event) -> std::vec::Vec<Output<Layer>> {
+        combos
+            .event(now, event, Layer::Base)
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn hit() {
+        let mut combos = Combos::new(COMBOS);
+        assert_eq!(event(&mut combos, 0, Press(1, 3)), []);
+        assert_eq!(
+            event(&mut combos, 10, Press(1, 4)),
+            [Output::Press(&COMBOS[0])]
+        );
+        assert_eq!(&combos.tick(100, Layer::Base)[..], &[]);
+        assert_eq!(
+            event(&mut combos, 150, Release(1, 3)),
+            [Output::Release(&COMBOS[0])]
+        );
+        assert_eq!(event(&mut combos, 160, Release(1, 4)), []);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut combos = Combos::new(COMBOS);
+        event(&mut combos, 0, Press(1, 3));
+        assert_eq!(&combos.tick(39, Layer::Base)[..], &[]);
+        assert_eq!(
+            &combos.tick(40, Layer::Base)[..],
+            &[Output::Event(Press(1, 3))]
+        );
+        // too late for the combo
+        assert_eq!(event(&mut combos, 50, Press(1, 4)), []);
+        assert_eq!(
+            &combos.tick(90, Layer::Base)[..],
+            &[Output::Event(Press(1, 4))]
+        );
+    }
+
+    #[test]
+    fn partial_miss() {
+        let mut combos = Combos::new(COMBOS);
+        event(&mut combos, 0, Press(1, 3));
+        assert_eq!(
+            event(&mut combos, 10, Press(0, 0)),
+            [Output::Event(Press(1, 3)), Output::Event(Press(0, 0))]
+        );
+
+        event(&mut combos, 100, Press(1, 3));
+        assert_eq!(
+            event(&mut combos, 110, Release(1, 3)),
+            [Output::Event(Press(1, 3)), Output::Event(Release(1, 3))]
+        );
+    }
+
+    #[test]
+    fn overlapping_combos() {
+        let mut combos = Combos::new(COMBOS);
+        event(&mut combos, 0, Press(1, 3));
+        // no combo has both, the first key goes and the second waits
+        assert_eq!(
+            event(&mut combos, 10, Press(1, 5)),
+            [Output::Event(Press(1, 3))]
+        );
+        assert_eq!(
+            event(&mut combos, 20, Press(1, 4)),
+            [Output::Press(&COMBOS[1])]
+        );
+    }
+
+    #[test]
+    fn release_order() {
+        let mut combos = Combos::new(COMBOS);
+        event(&mut combos, 0, Press(1, 4));
+        event(&mut combos, 10, Press(1, 3));
+        // the last key pressed is let go first, another key in between
+        assert_eq!(
+            event(&mut combos, 100, Release(1, 3)),
+            [Output::Release(&COMBOS[0])]
+        );
+        assert_eq!(
+            event(&mut combos, 110, Press(0, 0)),
+            [Output::Event(Press(0, 0))]
+        );
+        assert_eq!(event(&mut combos, 120, Release(1, 4)), []);
+        assert_eq!(
+            event(&mut combos, 130, Release(0, 0)),
+            [Output::Event(Release(0, 0))]
+        );
+    }
+
+    #[test]
+    fn other_layers() {
+        let mut combos = Combos::new(COMBOS);
+        assert_eq!(
+            event(&mut combos, 0, Press(2, 0)),
+            [Output::Event(Press(2, 0))]
+        );
+        assert_eq!(combos.event(100, Press(2, 1), Layer::Other)[..], []);
+        assert_eq!(
+            combos.event(110, Press(2, 0), Layer::Other)[..],
+            [Output::Press(&COMBOS[2])]
+        );
+    }
+}
@@ -1,12 +1,36 @@
-use crate::dispatcher::{menu::MenuAction, DisplayedState, Message};
+use crate::caps_word::CapsWord;
+use crate::combo::{self, Combo, ComboAction, Combos};
+use crate::dispatcher::{leader as leader_display, menu::MenuAction, DisplayedState, Message};
+use crate::dynamic_keymap::{self, DynamicKeymap, KeyEdit};
+use crate::encoder_mode::{self, Output};
+use crate::flash::Flash;
+use crate::key_override::Overrides;
 use crate::keyboard::*;
-use crate::keymap::Layer;
+use crate::keymap::{
+    self, Layer, CAPS_WORD_TIMEOUT, COMBOS, HOST_LAYOUT, KEY_OVERRIDES, LAYER_RULES,
+    LEADER_SEQUENCES, LEADER_TIMEOUT, ONE_SHOT, TYPING_INTERVAL,
+};
+use crate::layer_rules::LayerRules;
+use crate::leader::{Leader, LeaderAction, Step};
+use crate::macros::Macros;
+use crate::multi::{Multi, Multi::One};
+use crate::one_shot::{self, OneShot};
+use crate::rotary::Direction;
+use crate::store::{self, Store};
+use crate::switcher::{Session, Switcher};
+use crate::tap_hold::{self, TapDance, TapHold};
+use crate::typing::Typing;
 
 use keyberon::key_code::KeyCode;
-use keyberon::layout::{CustomEvent, Layout};
+use keyberon::layout::{CustomEvent, Event, Layers, Layout};
 
-use heapless::{consts::U8, spsc::Queue};
+use heapless::{
+    consts::{U4, U8},
+    spsc::Queue,
+    Vec,
+};
 
+#[derive(Clone, Copy)]
 pub enum PkbAction {
     MediaKey(MediaKey),
     MenuOpen,
@@ -16,28 +40,83 @@ pub enum PkbAction {
     MenuSelect,
     MenuLeft,
     MenuRight,
-    HoldCmd,
-    ReleaseCmd,
-    HoldCtrl,
-    ReleaseCtrl,
+    TapHold(KeyCode),
+    TapDance(&'static TapDance),
+    Leader,
+    MacroRecord(u8),
+    MacroPlay(u8),
+    SendString(&'static str),
+    OneShotMod(KeyCode),
+    OneShotLayer(Layer),
+    CapsWord,
+    Switcher(&'static Switcher),
+    EncoderTurn(Direction),
+    CycleEncoderMode,
 }
 
 pub struct CustomActionState {
-    hold_cmd: bool,
-    hold_ctrl: bool,
     current_layer: usize,
+    default_layer: usize,
     is_primary: bool,
     mk_reports: Queue<MediaKeyHidReport, U8>,
+    now: u32,
+    tap_hold: TapHold,
+    combos: Combos<Layer>,
+    toggled_from: Option<usize>,
+    keys: Vec<KeyCode, U4>,
+    leader: Leader,
+    typing: Typing,
+    macros: Macros,
+    one_shot: OneShot<Layer>,
+    one_shot_status: one_shot::Status<Layer>,
+    caps_word: CapsWord,
+    caps_word_shown: bool,
+    switcher: Session,
+    overrides: Overrides,
+    layer_rules: LayerRules,
+    keymap: DynamicKeymap,
+    remap: Remap,
+    remap_held: Vec<(u8, u8), U4>,
+    encoder_mode: encoder_mode::Mode,
+    menu_open: bool,
+}
+
+// Remapping takes two presses, the key to change and then the key whose
+// key code it gets.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Remap {
+    Off,
+    Target,
+    KeyCode(KeyEdit),
 }
 
 impl CustomActionState {
-    pub fn new() -> Self {
+    pub fn new(keymap: &'static mut [dynamic_keymap::Buffer; 2]) -> Self {
         CustomActionState {
-            hold_cmd: false,
-            hold_ctrl: false,
             current_layer: 0,
+            default_layer: 0,
             is_primary: false,
             mk_reports: Queue::new(),
+            now: 0,
+            tap_hold: TapHold::new(tap_hold::Config::default()),
+            combos: Combos::new(COMBOS),
+            toggled_from: None,
+            keys: Vec::new(),
+            leader: Leader::new(LEADER_SEQUENCES, LEADER_TIMEOUT),
+            typing: Typing::new(TYPING_INTERVAL, HOST_LAYOUT),
+            macros: Macros::default(),
+            one_shot: OneShot::new(ONE_SHOT),
+            one_shot_status: one_shot::Status::default(),
+            caps_word: CapsWord::new(CAPS_WORD_TIMEOUT),
+            caps_word_shown: false,
+            switcher: Session::default(),
+            overrides: Overrides::new(KEY_OVERRIDES),
+            layer_rules: LayerRules::new(LAYER_RULES),
+            keymap: DynamicKeymap::new(keymap),
+            remap: Remap::Off,
+            remap_held: Vec::new(),
+            encoder_mode: encoder_mode::Mode::default(),
+            menu_open: false,
         }
     }
 
@@ -45,6 +124,315 @@ impl CustomActionState {
         self.is_primary = true;
     }
 
+    pub fn set_default_layer(&mut self, layer: usize) {
+        self.default_layer = layer;
+    }
+
+    pub fn set_encoder_mode(&mut self, mode: encoder_mode::Mode) {
+        self.encoder_mode = mode;
+    }
+
+    /// Encoder turns move through the menu while it is shown.
+    pub fn set_menu_open(&mut self, open: bool) {
+        self.menu_open = open;
+    }
+
+    pub fn adjust_tap_hold(&mut self, action: tap_hold::Action) {
+        self.tap_hold.adjust(action);
+    }
+
+    pub fn load_macros(&mut self, store: &Store<Flash>) {
+        self.macros.load(store);
+    }
+
+    pub fn save_macros(&mut self, store: &mut Store<Flash>) -> Result<(), store::Error> {
+        self.macros.save(store)
+    }
+
+    /// What the layout should be created with, once the keymap is loaded.
+    pub fn layers(&self) -> Layers<PkbAction> {
+        self.keymap.layers()
+    }
+
+    pub fn load_keymap(&mut self, store: &Store<Flash>) {
+        self.keymap.load(store);
+    }
+
+    pub fn save_keymap(&mut self, store: &mut Store<Flash>) -> Result<(), store::Error> {
+        self.keymap.save(store)
+    }
+
+    /// Returns the new layers, for the dispatcher.
+    pub fn edit_key(
+        &mut self,
+        layout: &mut Layout<PkbAction>,
+        edit: KeyEdit,
+    ) -> Option<Layers<PkbAction>> {
+        let layers = self.keymap.edit(layout, edit)?;
+        layout.set_default_layer(self.default_layer);
+        Some(layers)
+    }
+
+    pub fn reset_keymap(&mut self, layout: &mut Layout<PkbAction>) -> Layers<PkbAction> {
+        let layers = self.keymap.reset(layout);
+        layout.set_default_layer(self.default_layer);
+        layers
+    }
+
+    pub fn start_remap(&mut self) {
+        self.remap = Remap::Target;
+    }
+
+    /// Feeds a matrix event to the layout, going through the leader key,
+    /// combo detection and then the tap-hold resolution first.
+    pub fn event(
+        &mut self,
+        layout: &mut Layout<PkbAction>,
+        event: Event,
+    ) -> Multi<Message> {
+        if let Some(messages) = self.remap_event(layout, event) {
+            return messages;
+        }
+
+        let layers = self.keymap.layers();
+        let (layer, default_layer) = (layout.current_layer(), self.default_layer);
+        let key_code = match event {
+            Event::Press(i, j) => keymap::key_code_for(layers, layer, default_layer, i, j),
+            Event::Release(_, _) => None,
+        };
+
+        match self.leader.event(self.now, event, key_code) {
+            Step::Pass => (),
+            Step::Swallow => return Multi::None,
+            Step::Captured(kc) => return One(Message::Leader(leader_display::Action::Key(kc as u8))),
+            Step::Matched(sequence) => {
+                return One(Message::Leader(leader_display::Action::End))
+                    .add(self.leader_action(sequence.action))
+            }
+            Step::Cancelled => return One(Message::Leader(leader_display::Action::End)),
+        }
+
+        let layer = Layer::from(layer);
+        let mut messages = Multi::None;
+        for output in self.combos.event(self.now, event, layer) {
+            match output {
+                combo::Output::Event(e) => self.tap_hold_event(layout, e),
+                combo::Output::Press(c) => {
+                    if let Some(m) = self.combo_press(c) {
+                        messages.append(m);
+                    }
+                }
+                combo::Output::Release(c) => self.combo_release(c),
+            }
+        }
+        if let Some(m) = self.one_shot_changed() {
+            messages.append(m);
+        }
+        messages
+    }
+
+    /// Called once per scan, i.e. every millisecond.
+    pub fn tick(&mut self, layout: &mut Layout<PkbAction>) -> impl IntoIterator<Item = Message> {
+        self.now = self.now.wrapping_add(1);
+        self.typing.tick();
+
+        self.macros.tick();
+        let mut messages = Multi::None;
+
+        let layer = Layer::from(layout.current_layer());
+        for output in self.combos.tick(self.now, layer) {
+            if let combo::Output::Event(e) = output {
+                self.tap_hold_event(layout, e);
+            }
+        }
+
+        let layers = self.keymap.layers();
+        let (layer, default_layer) = (self.tap_layer(layout), self.default_layer);
+        let outputs = self.tap_hold.tick(self.now, |i, j| {
+            keymap::tap_for(layers, layer, default_layer, i, j)
+        });
+        self.apply(layout, outputs);
+
+        self.one_shot.tick(self.now);
+        self.caps_word.tick(self.now);
+        self.switcher.tick(self.now);
+
+        if let Some(Step::Cancelled) = self.leader.tick(self.now) {
+            messages.append(Message::Leader(leader_display::Action::End));
+        }
+        if let Some(m) = self.one_shot_changed() {
+            messages.append(m);
+        }
+        // caps word also ends from the report, which has no way to tell
+        if self.caps_word.is_active() != self.caps_word_shown {
+            self.caps_word_shown = self.caps_word.is_active();
+            messages.append(Message::CapsWord(self.caps_word_shown));
+        }
+        messages
+    }
+
+    // The first key pressed is the one to change, on the current layer, the
+    // second gives its key code. Layer keys still work, to get to other
+    // layers, and the releases of the two keys are swallowed too.
+    fn remap_event(&mut self, layout: &Layout<PkbAction>, event: Event) -> Option<Multi<Message>> {
+        let layers = self.keymap.layers();
+        let (layer, default_layer) = (layout.current_layer(), self.default_layer);
+        match event {
+            Event::Release(i, j) => {
+                let k = self.remap_held.iter().position(|c| *c == (i, j))?;
+                self.remap_held.swap_remove(k);
+                Some(Multi::None)
+            }
+            Event::Press(_, _) if self.remap == Remap::Off => None,
+            Event::Press(i, j)
+                if keymap::layer_for(layers, layer, default_layer, i, j).is_some() =>
+            {
+                None
+            }
+            Event::Press(i, j) => {
+                self.remap_held.push((i, j)).ok();
+                match self.remap {
+                    Remap::Target => {
+                        self.remap = Remap::KeyCode(KeyEdit {
+                            layer: layer as u8,
+                            i,
+                            j,
+                            key_code: 0,
+                        });
+                        Some(Multi::None)
+                    }
+                    Remap::KeyCode(edit) => {
+                        self.remap = Remap::Off;
+                        let mut messages = One(Message::Remap(false));
+                        if let Some(kc) = keymap::key_code_for(layers, layer, default_layer, i, j) {
+                            messages.append(Message::EditKey(KeyEdit {
+                                key_code: kc as u8,
+                                ..edit
+                            }));
+                        }
+                        Some(messages)
+                    }
+                    Remap::Off => None,
+                }
+            }
+        }
+    }
+
+    fn one_shot_changed(&mut self) -> Option<Message> {
+        let status = self.one_shot.status();
+        if status != self.one_shot_status {
+            self.one_shot_status = status;
+            Some(Message::OneShot(status))
+        } else {
+            None
+        }
+    }
+
+    fn leader_action(&mut self, action: LeaderAction) -> Multi<Message> {
+        match action {
+            LeaderAction::Keys(chords) => {
+                for chord in chords.iter() {
+                    self.typing.type_chord(chord);
+                }
+                Multi::None
+            }
+            LeaderAction::Message(m) => One(m),
+            LeaderAction::Layer(layer) => One(Message::SetDefaultLayer(layer.into())),
+            LeaderAction::Unicode(c) => {
+                self.typing.type_unicode(c);
+                Multi::None
+            }
+        }
+    }
+
+    // A pending one-shot layer isn't held in the layout until the next key
+    // is pressed, but that key's tap and one-shot are on it.
+    fn tap_layer(&self, layout: &Layout<PkbAction>) -> usize {
+        self.one_shot
+            .layer()
+            .map_or(layout.current_layer(), usize::from)
+    }
+
+    fn tap_hold_event(&mut self, layout: &mut Layout<PkbAction>, event: Event) {
+        let layers = self.keymap.layers();
+        let (layer, default_layer) = (self.tap_layer(layout), self.default_layer);
+        let outputs = self.tap_hold.event(self.now, event, |i, j| {
+            keymap::tap_for(layers, layer, default_layer, i, j)
+        });
+        self.apply(layout, outputs);
+    }
+
+    fn apply(&mut self, layout: &mut Layout<PkbAction>, outputs: tap_hold::Outputs) {
+        let layers = self.keymap.layers();
+        let (layer, default_layer) = (self.tap_layer(layout), self.default_layer);
+        for output in outputs {
+            let events = match output {
+                tap_hold::Output::Event(e) => {
+                    let key = match e {
+                        Event::Press(i, j) => {
+                            keymap::one_shot_for(layers, layer, default_layer, i, j)
+                        }
+                        Event::Release(_, _) => None,
+                    };
+                    self.one_shot.event(self.now, e, key)
+                }
+                tap_hold::Output::TapPress(kc) => {
+                    self.press_key(kc);
+                    self.one_shot.tap_press(kc)
+                }
+                tap_hold::Output::TapRelease(kc) => {
+                    self.release_key(kc);
+                    self.one_shot.tap_release(kc)
+                }
+            };
+            for e in events {
+                let held = match e {
+                    Event::Press(i, j) => keymap::layer_for(layers, layer, default_layer, i, j),
+                    Event::Release(_, _) => None,
+                };
+                for e in self.layer_rules.event(e, held) {
+                    layout.event(e);
+                }
+            }
+        }
+    }
+
+    fn combo_press(&mut self, combo: &Combo<Layer>) -> Option<Message> {
+        match combo.action {
+            ComboAction::Key(kc) => {
+                self.press_key(kc);
+                None
+            }
+            ComboAction::ToggleLayer(layer) => {
+                let layer = usize::from(layer);
+                if self.default_layer == layer {
+                    Some(Message::SetDefaultLayer(self.toggled_from.take().unwrap_or(0)))
+                } else {
+                    if self.toggled_from.is_none() {
+                        self.toggled_from = Some(self.default_layer);
+                    }
+                    Some(Message::SetDefaultLayer(layer))
+                }
+            }
+        }
+    }
+
+    fn combo_release(&mut self, combo: &Combo<Layer>) {
+        if let ComboAction::Key(kc) = combo.action {
+            self.release_key(kc);
+        }
+    }
+
+    fn press_key(&mut self, kc: KeyCode) {
+        self.keys.push(kc).ok();
+    }
+
+    fn release_key(&mut self, kc: KeyCode) {
+        if let Some(i) = self.keys.iter().position(|k| *k == kc) {
+            self.keys.swap_remove(i);
+        }
+    }
+
     #[inline]
     pub fn process(&mut self, event: CustomEvent<PkbAction>) -> impl IntoIterator<Item = Message> {
         match event {
@@ -56,21 +444,51 @@ impl CustomActionState {
                 self.mk_reports.enqueue(MediaKeyHidReport::default()).ok();
                 None
             }
-            CustomEvent::Press(PkbAction::HoldCmd) => {
-                self.hold_cmd = true;
-                Some(Message::CmdHeld)
+            CustomEvent::Press(PkbAction::Switcher(switcher)) => {
+                self.switcher.start(switcher, self.now);
+                None
             }
-            CustomEvent::Release(PkbAction::ReleaseCmd) => {
-                self.hold_cmd = false;
-                Some(Message::CmdReleased)
+            CustomEvent::Press(PkbAction::Leader) => {
+                self.leader.start(self.now);
+                Some(Message::Leader(leader_display::Action::Start))
             }
-            CustomEvent::Press(PkbAction::HoldCtrl) => {
-                self.hold_ctrl = true;
-                Some(Message::CtrlHeld)
+            CustomEvent::Press(PkbAction::MacroRecord(slot)) => {
+                if self.macros.is_recording() {
+                    self.macros.stop_recording();
+                    Some(Message::Recording(None))
+                } else if self.macros.start_recording(*slot as usize, self.now) {
+                    Some(Message::Recording(Some(*slot)))
+                } else {
+                    None
+                }
             }
-            CustomEvent::Release(PkbAction::ReleaseCtrl) => {
-                self.hold_ctrl = false;
-                Some(Message::CtrlReleased)
+            CustomEvent::Press(PkbAction::CapsWord) => {
+                self.caps_word.toggle(self.now);
+                None
+            }
+            CustomEvent::Press(PkbAction::SendString(s)) => {
+                self.typing.type_str(*s);
+                None
+            }
+            CustomEvent::Press(PkbAction::MacroPlay(slot)) => {
+                self.macros.play(*slot as usize);
+                None
+            }
+            CustomEvent::Press(PkbAction::EncoderTurn(d)) if self.menu_open => {
+                Some(Message::Menu(MenuAction::Turn(*d)))
+            }
+            CustomEvent::Press(PkbAction::EncoderTurn(d)) => {
+                match encoder_mode::turn(self.encoder_mode, *d) {
+                    Output::Keys(keys) => {
+                        self.typing.type_chord(keys);
+                        None
+                    }
+                    Output::Message(m) => Some(m),
+                }
+            }
+            CustomEvent::Press(PkbAction::CycleEncoderMode) => {
+                self.encoder_mode = self.encoder_mode.next();
+                Some(Message::EncoderMode(self.encoder_mode))
             }
             CustomEvent::Release(PkbAction::MenuOpen) => {
                 if self.is_primary {
@@ -93,6 +511,8 @@ impl CustomActionState {
         self.mk_reports.dequeue()
     }
 
+    /// The layout holds the result of any matching layer rule itself, so
+    /// its current layer is the effective one.
     pub fn check_layout_for_events(
         &mut self,
         layout: &Layout<PkbAction>,
@@ -110,13 +530,28 @@ impl CustomActionState {
         }
     }
 
-    pub fn modify_kb_report(&self, report: &mut KbHidReport) {
-        if self.hold_cmd {
-            report.pressed(KeyCode::LGui);
+    pub fn modify_kb_report(&mut self, report: &mut KbHidReport) {
+        for kc in self.keys.iter() {
+            report.pressed(*kc);
+        }
+
+        for kc in self.one_shot.mods() {
+            report.pressed(kc);
+        }
+
+        self.overrides.modify_report(report);
+        self.switcher.modify_report(self.now, report);
+        self.caps_word.modify_report(self.now, report);
+
+        // typed text already has the key codes and shift it needs
+        for kc in self.typing.keys() {
+            report.pressed(*kc);
         }
 
-        if self.hold_ctrl {
-            report.pressed(KeyCode::LCtrl);
+        // a macro records and plays back what the host gets
+        self.macros.record(self.now, report);
+        for code in self.macros.keys() {
+            report.pressed_code(*code);
         }
     }
 }
@@ -0,0 +1,258 @@
+use keyberon::layout::Event;
+use serde::{Deserialize, Serialize};
+
+// A half's matrix.
+pub const ROWS: usize = 4;
+pub const COLUMNS: usize = 7;
+const MAX_TIME: u8 = 30;
+
+pub type Keys = [[bool; COLUMNS]; ROWS];
+
+/// How a switch settles before its change is sent.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Strategy {
+    /// A press is sent at once and the key isn't read again for `time`, a
+    /// release is sent once the key has been up for `time`.
+    EagerPress,
+    /// Presses and releases are sent once the key has stayed that way for
+    /// `time`.
+    Defer,
+    /// Every change is held back until no key has changed for `time`.
+    Global,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Action {
+    Strategy(Strategy),
+    IncrementTime,
+    DecrementTime,
+}
+
+/// Timings are in milliseconds, i.e. scan ticks.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Config {
+    pub strategy: Strategy,
+    pub time: u8,
+}
+
+impl Config {
+    pub fn apply(&mut self, action: Action) {
+        match action {
+            Action::Strategy(strategy) => self.strategy = strategy,
+            Action::IncrementTime => self.time = core::cmp::min(MAX_TIME, self.time + 1),
+            Action::DecrementTime => self.time = self.time.saturating_sub(1),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Key {
+    // what was last sent
+    pressed: bool,
+    // what was last read
+    raw: bool,
+    // scans the key has read `raw` for
+    stable: u8,
+    // scans since `pressed` was sent
+    since_sent: u8,
+}
+
+/// Turns the matrix read every scan into key presses and releases.
+pub struct Debouncer {
+    config: Config,
+    keys: [[Key; COLUMNS]; ROWS],
+    // scans since any key changed
+    matrix_stable: u8,
+}
+
+impl Debouncer {
+    pub fn new(config: Config) -> Self {
+        Debouncer {
+            config,
+            keys: [[Key::default(); COLUMNS]; ROWS],
+            matrix_stable: 0,
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn apply(&mut self, action: Action) {
+        self.config.apply(action);
+    }
+
+    /// Called every scan with the keys read, in the matrix's coordinates.
+    pub fn events(&mut self, read: Keys) -> impl Iterator<Item = Event> + '_ {
+        let mut changed = false;
+        for (keys, read) in self.keys.iter_mut().zip(read.iter()) {
+            for (key, raw) in keys.iter_mut().zip(read.iter()) {
+                if key.raw != *raw {
+                    key.raw = *raw;
+                    key.stable = 0;
+                    changed = true;
+                }
+                key.stable = key.stable.saturating_add(1);
+                key.since_sent = key.since_sent.saturating_add(1);
+            }
+        }
+        self.matrix_stable = if changed {
+            1
+        } else {
+            self.matrix_stable.saturating_add(1)
+        };
+
+        let (config, matrix_stable) = (self.config, self.matrix_stable);
+        self.keys.iter_mut().enumerate().flat_map(move |(i, keys)| {
+            keys.iter_mut().enumerate().filter_map(move |(j, key)| {
+                if !settled(config, matrix_stable, key) {
+                    return None;
+                }
+                key.pressed = key.raw;
+                key.since_sent = 0;
+                if key.pressed {
+                    Some(Event::Press(i as u8, j as u8))
+                } else {
+                    Some(Event::Release(i as u8, j as u8))
+                }
+            })
+        })
+    }
+}
+
+// Whether the key's change is to be sent now.
+fn settled(config: Config, matrix_stable: u8, key: &Key) -> bool {
+    if key.raw == key.pressed {
+        return false;
+    }
+    match (config.strategy, key.raw) {
+        (Strategy::EagerPress, true) => key.since_sent >= config.time,
+        (Strategy::EagerPress, false) | (Strategy::Defer, _) => key.stable >= config.time,
+        (Strategy::Global, _) => matrix_stable >= config.time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Press, Release};
+
+    const A: (usize, usize) = (0, 0);
+    const B: (usize, usize) = (1, 1);
+
+    // A switch chattering as it's pressed, and again as it's let go of, with
+    // what each strategy sends and on which scan.
+    const PRESS_AND_RELEASE: &[(usize, (usize, usize), bool)] = &[
+        (10, A, true),
+        (11, A, false),
+        (12, A, true),
+        (24, A, false),
+        (25, A, true),
+        (26, A, false),
+    ];
+
+    // `B` bounces while `A` is pressed cleanly.
+    const ROLL: &[(usize, (usize, usize), bool)] =
+        &[(10, A, true), (12, B, true), (13, B, false), (14, B, true)];
+
+    fn config(strategy: Strategy) -> Config {
+        Config { strategy, time: 5 }
+    }
+
+    // The events sent over 40 scans of the matrix changing as in `trace`.
+    fn debounce(
+        config: Config,
+        trace: &[(usize, (usize, usize), bool)],
+    ) -> std::vec::Vec<(usize, Event)> {
+        let mut debouncer = Debouncer::new(config);
+        let mut read: Keys = [[false; COLUMNS]; ROWS];
+        let mut sent = std::vec::Vec::new();
+        for scan in 0..40 {
+            for (at, (i, j), pressed) in trace.iter() {
+                if *at == scan {
+                    read[*i][*j] = *pressed;
+                }
+            }
+            sent.extend(debouncer.events(read).map(|e| (scan, e)));
+        }
+        sent
+    }
+
+    #[test]
+    fn traces() {
+        let cases: [(Strategy, _, &[(usize, Event)]); 6] = [
+            // the press goes out at once, the release once it has settled
+            (
+                Strategy::EagerPress,
+                PRESS_AND_RELEASE,
+                &[(10, Press(0, 0)), (30, Release(0, 0))],
+            ),
+            (
+                Strategy::Defer,
+                PRESS_AND_RELEASE,
+                &[(16, Press(0, 0)), (30, Release(0, 0))],
+            ),
+            (
+                Strategy::Global,
+                PRESS_AND_RELEASE,
+                &[(16, Press(0, 0)), (30, Release(0, 0))],
+            ),
+            // each key settles on its own
+            (
+                Strategy::EagerPress,
+                ROLL,
+                &[(10, Press(0, 0)), (12, Press(1, 1))],
+            ),
+            (
+                Strategy::Defer,
+                ROLL,
+                &[(14, Press(0, 0)), (18, Press(1, 1))],
+            ),
+            // the bounce holds back every key
+            (
+                Strategy::Global,
+                ROLL,
+                &[(18, Press(0, 0)), (18, Press(1, 1))],
+            ),
+        ];
+        for (strategy, trace, expected) in cases.iter() {
+            assert_eq!(
+                debounce(config(*strategy), trace),
+                *expected,
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn no_time() {
+        for strategy in [Strategy::EagerPress, Strategy::Defer, Strategy::Global].iter() {
+            assert_eq!(
+                debounce(
+                    Config {
+                        strategy: *strategy,
+                        time: 0
+                    },
+                    PRESS_AND_RELEASE
+                ),
+                [
+                    (10, Press(0, 0)),
+                    (11, Release(0, 0)),
+                    (12, Press(0, 0)),
+                    (24, Release(0, 0)),
+                    (25, Press(0, 0)),
+                    (26, Release(0, 0)),
+                ],
+                "{:?}",
+                strategy
+            );
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+
+use crate::debounce::{Keys, COLUMNS, ROWS};
+
+// Milliseconds, i.e. scan ticks. A reading changing again this soon after
+// the last change is still the same press or release bouncing.
+const BURST: u32 = 20;
+// Still bouncing this long after a press or release started, the switch
+// chatters more than debouncing hides.
+const CHATTER: u32 = 10;
+// Held this long, the switch is more likely stuck than held.
+const STUCK: u32 = 60_000;
+// A key isn't reported again this soon after the last time.
+const QUIET: u32 = 1000;
+
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Kind {
+    /// Bounced for longer than a few milliseconds.
+    Chatter,
+    /// Held for a minute.
+    Stuck,
+    /// Changed on the same scan as other keys in its row, and did again
+    /// while still bouncing. A chord changes them together once, a wiring
+    /// fault in the row again and again.
+    RowNoise,
+}
+
+/// Something suspicious about the switch at `(i, j)` of this half's matrix,
+/// `at` milliseconds after it started scanning.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Report {
+    pub kind: Kind,
+    pub i: u8,
+    pub j: u8,
+    pub at: u32,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Key {
+    pressed: bool,
+    last_change: Option<u32>,
+    // when the changes the last one belongs to started
+    burst_start: u32,
+    // the last change was on the same scan as others in the row
+    row_change: bool,
+    chattering: bool,
+    stuck: bool,
+    reported: Option<u32>,
+}
+
+impl Key {
+    fn update(&mut self, now: u32, pressed: bool, row_change: bool) -> Option<Kind> {
+        if pressed == self.pressed {
+            let held = self.last_change.map(|at| now.wrapping_sub(at));
+            if pressed && !self.stuck && matches!(held, Some(h) if h >= STUCK) {
+                self.stuck = true;
+                return Some(Kind::Stuck);
+            }
+            return None;
+        }
+
+        let since = self.last_change.map(|at| now.wrapping_sub(at));
+        let bouncing = matches!(since, Some(s) if s < BURST);
+        if !bouncing {
+            self.burst_start = now;
+            self.chattering = false;
+        }
+        let repeated = row_change && self.row_change && bouncing;
+        self.pressed = pressed;
+        self.last_change = Some(now);
+        self.row_change = row_change;
+        self.stuck = false;
+
+        if repeated {
+            Some(Kind::RowNoise)
+        } else if !self.chattering && now.wrapping_sub(self.burst_start) >= CHATTER {
+            self.chattering = true;
+            Some(Kind::Chatter)
+        } else {
+            None
+        }
+    }
+
+    fn may_report(&self, now: u32) -> bool {
+        !matches!(self.reported, Some(at) if now.wrapping_sub(at) < QUIET)
+    }
+}
+
+/// Watches the raw reads of the scans, before debouncing hides them, for
+/// worn switches.
+pub struct Detector {
+    now: u32,
+    keys: [[Key; COLUMNS]; ROWS],
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Detector {
+            now: 0,
+            keys: [[Key::default(); COLUMNS]; ROWS],
+        }
+    }
+}
+
+impl Detector {
+    /// Called every scan with the keys read. So a worn switch can't flood
+    /// the dispatcher, reports one thing a scan, and a key at most once a
+    /// second.
+    pub fn scan(&mut self, read: &Keys) -> Option<Report> {
+        self.now = self.now.wrapping_add(1);
+
+        let now = self.now;
+        let mut found = None;
+        for (i, (keys, read)) in self.keys.iter_mut().zip(read.iter()).enumerate() {
+            let changes = keys
+                .iter()
+                .zip(read.iter())
+                .filter(|(key, pressed)| key.pressed != **pressed)
+                .count();
+            for (j, (key, pressed)) in keys.iter_mut().zip(read.iter()).enumerate() {
+                let kind = match key.update(now, *pressed, changes > 1) {
+                    Some(kind) => kind,
+                    _ => continue,
+                };
+                if found.is_none() && key.may_report(now) {
+                    key.reported = Some(now);
+                    found = Some(report(kind, i, j, now));
+                }
+            }
+        }
+        found
+    }
+}
+
+fn report(kind: Kind, i: usize, j: usize, at: u32) -> Report {
+    Report {
+        kind,
+        i: i as u8,
+        j: j as u8,
+        at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // Scans `times` times with only the keys given pressed, returning the
+    // reports.
+    fn scan(detector: &mut Detector, pressed: &[(usize, usize)], times: u32) -> Vec<Report> {
+        let mut read: Keys = Default::default();
+        for &(i, j) in pressed {
+            read[i][j] = true;
+        }
+        (0..times).filter_map(|_| detector.scan(&read)).collect()
+    }
+
+    #[test]
+    fn short_bounces_are_not_reported() {
+        let mut detector = Detector::default();
+        for _ in 0..3 {
+            assert!(scan(&mut detector, &[(1, 2)], 1).is_empty());
+            assert!(scan(&mut detector, &[], 1).is_empty());
+        }
+        assert!(scan(&mut detector, &[(1, 2)], 100).is_empty());
+        assert!(scan(&mut detector, &[], 100).is_empty());
+    }
+
+    #[test]
+    fn long_bounces_are_chatter() {
+        let mut detector = Detector::default();
+        let mut reports = Vec::new();
+        for _ in 0..8 {
+            reports.extend(scan(&mut detector, &[(1, 2)], 1));
+            reports.extend(scan(&mut detector, &[], 1));
+        }
+        assert_eq!(
+            reports,
+            [Report {
+                kind: Kind::Chatter,
+                i: 1,
+                j: 2,
+                at: 11
+            }]
+        );
+    }
+
+    #[test]
+    fn held_for_a_minute_is_stuck() {
+        let mut detector = Detector::default();
+        let reports = scan(&mut detector, &[(3, 0)], STUCK + 10);
+        assert_eq!(
+            reports,
+            [Report {
+                kind: Kind::Stuck,
+                i: 3,
+                j: 0,
+                at: STUCK + 1
+            }]
+        );
+    }
+
+    #[test]
+    fn chords_are_not_row_noise() {
+        let mut detector = Detector::default();
+        // the keys of a combo, pressed and let go of together
+        for _ in 0..3 {
+            assert!(scan(&mut detector, &[(1, 3), (1, 4)], 50).is_empty());
+            assert!(scan(&mut detector, &[], 50).is_empty());
+        }
+    }
+
+    #[test]
+    fn row_noise_is_one_report_a_scan() {
+        let mut detector = Detector::default();
+        assert!(scan(&mut detector, &[(0, 1), (0, 4)], 1).is_empty());
+        assert_eq!(
+            scan(&mut detector, &[], 1),
+            [Report {
+                kind: Kind::RowNoise,
+                i: 0,
+                j: 1,
+                at: 2
+            }]
+        );
+        // the first key is quiet now
+        assert_eq!(
+            scan(&mut detector, &[(0, 1), (0, 4)], 1),
+            [Report {
+                kind: Kind::RowNoise,
+                i: 0,
+                j: 4,
+                at: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn a_key_is_quiet_for_a_second_after_a_report() {
+        let mut detector = Detector::default();
+        let mut reports = 0;
+        for _ in 0..QUIET / 2 {
+            reports += scan(&mut detector, &[(0, 1), (0, 4)], 1).len();
+            reports += scan(&mut detector, &[], 1).len();
+        }
+        // both keys once
+        assert_eq!(reports, 2);
+        reports += scan(&mut detector, &[(0, 1), (0, 4)], 1).len();
+        reports += scan(&mut detector, &[], 1).len();
+        assert_eq!(reports, 3);
+    }
+}
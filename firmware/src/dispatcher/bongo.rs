@@ -1,8 +1,18 @@
 use super::*;
 use embedded_graphics::{
+    fonts::{Font6x8, Text},
     image::{Image, ImageRaw},
     pixelcolor::BinaryColor,
+    style::TextStyleBuilder,
 };
+use numtoa::NumToA;
+
+// Below this the cat waits with its paws up instead of tapping.
+const TAPPING_WPM: u8 = 20;
+// A paw goes down each time the phase passes this, it goes up by the wpm
+// every display update. At 24 updates a second and five presses a word
+// that's a tap per key press.
+const TAP_PHASE: u16 = 60 * 24 / 5;
 
 #[rustfmt::skip]
 const ANI : &[&[u8]] = &[
@@ -140,22 +150,15 @@ const WAITING : &[u8] = &[
 ];
 
 pub struct Bongo {
-    typing: u32,
-    frame: u8,
+    wpm: u8,
+    phase: u16,
+    left_paw: bool,
     waiting: ImageRaw<'static, BinaryColor>,
     ready: ImageRaw<'static, BinaryColor>,
     left: ImageRaw<'static, BinaryColor>,
     right: ImageRaw<'static, BinaryColor>,
 }
 
-impl Bongo {
-    fn bongo(&mut self) {
-        if self.typing < 70 {
-            self.typing += 7
-        }
-    }
-}
-
 impl<'a> Default for Bongo {
     fn default() -> Self {
         let waiting = ImageRaw::new(WAITING, 64, 30);
@@ -168,8 +171,9 @@ impl<'a> Default for Bongo {
             ready,
             left,
             right,
-            typing: 0,
-            frame: 0,
+            wpm: 0,
+            phase: 0,
+            left_paw: true,
         }
     }
 }
@@ -179,22 +183,10 @@ impl State for Bongo {
 
     #[inline]
     fn handle_event(&mut self, message: Message) -> Self::Messages {
-        match message {
-            Message::MatrixKeyRelease(_, _) => {
-                self.bongo();
-                Some(Message::Bongo)
-            }
-            Message::Bongo => {
-                self.bongo();
-                None
-            }
-            Message::DisplaySelect(DisplayedState::Bongo)
-            | Message::SecondaryDisplaySelect(DisplayedState::Bongo) => {
-                self.typing = 0;
-                None
-            }
-            _ => None,
+        if let Message::Wpm(wpm) = message {
+            self.wpm = wpm;
         }
+        None
     }
 
     fn write_to_display<DI, DSIZE>(&mut self, display: &mut GraphicsMode<DI, DSIZE>)
@@ -203,27 +195,39 @@ impl State for Bongo {
         DI: WriteOnlyDataCommand,
     {
         display.clear();
-        if self.typing > 0 {
-            self.typing -= 1;
-        }
 
-        let image: Image<_, BinaryColor> = match self.typing {
+        let image: Image<_, BinaryColor> = match self.wpm {
             0 => Image::new(&self.waiting, Point::new(0, 31)),
-            1..=20 => Image::new(&self.ready, Point::new(0, 31)),
-            _ => {
-                if self.frame == 24 {
-                    self.frame = 0;
-                } else {
-                    self.frame += 1;
+            w if w < TAPPING_WPM => Image::new(&self.ready, Point::new(0, 31)),
+            w => {
+                self.phase += w as u16;
+                if self.phase >= TAP_PHASE {
+                    self.phase -= TAP_PHASE;
+                    self.left_paw = !self.left_paw;
                 }
-                match self.frame % 12 {
-                    0..=6 => Image::new(&self.left, Point::new(0, 31)),
-                    _ => Image::new(&self.right, Point::new(0, 31)),
+                if self.left_paw {
+                    Image::new(&self.left, Point::new(0, 31))
+                } else {
+                    Image::new(&self.right, Point::new(0, 31))
                 }
             }
         };
         image.draw(display).unwrap();
 
+        let font_6x8 = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+        let mut buffer: [u8; 20] = [0; 20];
+
+        Text::new("wpm:", Point::new(0, 78))
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+        Text::new(self.wpm.numtoa_str(10, &mut buffer), Point::new(24, 78))
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
         display.flush().ok();
     }
 }
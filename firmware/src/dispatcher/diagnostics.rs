@@ -0,0 +1,191 @@
+use super::*;
+
+use embedded_graphics::{
+    fonts::{Font6x8, Text},
+    pixelcolor::BinaryColor,
+    style::TextStyleBuilder,
+};
+
+use crate::diagnostics::Kind;
+use crate::multi::{Multi, Multi::*};
+
+use numtoa::NumToA;
+
+const ROWS: usize = 4;
+const COLUMNS: usize = 7;
+// keys on the screen, two lines each
+const SHOWN: usize = 4;
+
+#[derive(Copy, Clone, Default)]
+struct Counts {
+    chatter: u16,
+    stuck: u16,
+    row_noise: u16,
+    // milliseconds after scanning started
+    last_seen: Option<u32>,
+}
+
+impl Counts {
+    fn add(&mut self, report: Report) {
+        let count = match report.kind {
+            Kind::Chatter => &mut self.chatter,
+            Kind::Stuck => &mut self.stuck,
+            Kind::RowNoise => &mut self.row_noise,
+        };
+        *count = count.saturating_add(1);
+        self.last_seen = Some(report.at);
+    }
+}
+
+/// What the scans of this half found, the switches seen most recently first.
+#[derive(Default)]
+pub struct Diagnostics {
+    keys: [[Counts; COLUMNS]; ROWS],
+}
+
+impl Diagnostics {
+    // Over this half's debug probe, every switch that has been reported.
+    fn dump(&self) {
+        defmt::info!("matrix diagnostics");
+        for (i, keys) in self.keys.iter().enumerate() {
+            for (j, counts) in keys.iter().enumerate() {
+                if let Some(at) = counts.last_seen {
+                    defmt::info!(
+                        "({}, {}): chatter {} stuck {} row {}, last at {} ms",
+                        i as u8,
+                        j as u8,
+                        counts.chatter,
+                        counts.stuck,
+                        counts.row_noise,
+                        at
+                    );
+                }
+            }
+        }
+    }
+
+    fn most_recent(&self) -> [Option<(u32, usize, usize)>; SHOWN] {
+        let mut shown = [Option::None; SHOWN];
+        for (i, keys) in self.keys.iter().enumerate() {
+            for (j, counts) in keys.iter().enumerate() {
+                let at = match counts.last_seen {
+                    Some(at) => at,
+                    _ => continue,
+                };
+                let later = |s: &Option<(u32, usize, usize)>| s.map_or(true, |(a, _, _)| at > a);
+                if let Some(k) = shown.iter().position(later) {
+                    for n in (k + 1..SHOWN).rev() {
+                        shown[n] = shown[n - 1];
+                    }
+                    shown[k] = Some((at, i, j));
+                }
+            }
+        }
+        shown
+    }
+}
+
+impl State for Diagnostics {
+    type Messages = Multi<Message>;
+
+    fn handle_event(&mut self, message: Message) -> Self::Messages {
+        match message {
+            Message::Diagnostic(report) => {
+                let counts = self
+                    .keys
+                    .get_mut(report.i as usize)
+                    .and_then(|r| r.get_mut(report.j as usize));
+                if let Some(counts) = counts {
+                    counts.add(report);
+                }
+                None
+            }
+            Message::DumpDiagnostics => {
+                self.dump();
+                One(Message::SecondaryDumpDiagnostics)
+            }
+            Message::SecondaryDumpDiagnostics => {
+                self.dump();
+                None
+            }
+            Message::ClearDiagnostics => {
+                *self = Diagnostics::default();
+                One(Message::SecondaryClearDiagnostics)
+            }
+            Message::SecondaryClearDiagnostics => {
+                *self = Diagnostics::default();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn write_to_display<DI, DSIZE>(&mut self, display: &mut GraphicsMode<DI, DSIZE>)
+    where
+        DSIZE: DisplaySize,
+        DI: WriteOnlyDataCommand,
+    {
+        display.clear();
+        let font_6x8 = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let mut buffer: [u8; 20] = [0; 20];
+
+        Text::new("switches", Point::zero())
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
+        for (n, (at, i, j)) in self.most_recent().iter().flatten().enumerate() {
+            let counts = self.keys[*i][*j];
+            let y = 13 + 26 * n as i32;
+
+            // the key, and the second of scanning it was last reported at
+            Text::new(i.numtoa_str(10, &mut buffer), Point::new(0, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+            Text::new(",", Point::new(6, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+            Text::new(j.numtoa_str(10, &mut buffer), Point::new(12, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+            Text::new("@", Point::new(24, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+            Text::new((at / 1000).numtoa_str(10, &mut buffer), Point::new(30, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+
+            // chatter, stuck and row noise, up to 99
+            let y = y + 13;
+            for (x, label, count) in [
+                (0, "c", counts.chatter),
+                (22, "s", counts.stuck),
+                (44, "r", counts.row_noise),
+            ]
+            .iter()
+            {
+                Text::new(label, Point::new(*x, y))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+                Text::new(
+                    (*count).min(99).numtoa_str(10, &mut buffer),
+                    Point::new(x + 6, y),
+                )
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+            }
+        }
+
+        display.flush().unwrap();
+    }
+}
@@ -1,4 +1,9 @@
+use crate::encoder_mode;
+use crate::keymap::ROWS;
 use crate::multi::{Multi, Multi::*};
+use crate::one_shot;
+use crate::rotary::{self, Encoder};
+use crate::wpm::Wpm;
 
 use super::*;
 
@@ -8,31 +13,77 @@ use embedded_graphics::{
     style::TextStyleBuilder,
 };
 use keyberon::layout::Event;
+use numtoa::NumToA;
+
+// minutes without a key press before both halves sleep
+const SLEEP_TIMEOUT: u8 = 3;
+const MAX_SLEEP_TIMEOUT: u8 = 60;
+
+// display updates per minute
+const TICKS_PER_MINUTE: u32 = 24 * 60;
 
-#[derive(Default)]
 pub struct Info {
     usb_connected: bool,
     hand: Option<Hand>,
     last_matrix: Option<Event>,
-    cmd_held: bool,
-    ctrl_held: bool,
     current_layer: Layer,
+    recording: Option<u8>,
+    one_shot: one_shot::Status<Layer>,
+    caps_word: bool,
+    remapping: bool,
+    encoder_mode: encoder_mode::Mode,
     ticks_since_press: u32,
+    sleep_timeout: u8,
+    // only the primary sees every key, the secondary shows what it sends
+    wpm: Wpm,
+    shown_wpm: u8,
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Info {
+            usb_connected: false,
+            hand: None,
+            last_matrix: None,
+            current_layer: Layer::default(),
+            recording: None,
+            one_shot: one_shot::Status::default(),
+            caps_word: false,
+            remapping: false,
+            encoder_mode: encoder_mode::Mode::default(),
+            ticks_since_press: 0,
+            sleep_timeout: SLEEP_TIMEOUT,
+            wpm: Wpm::default(),
+            shown_wpm: 0,
+        }
+    }
 }
 
 impl Info {
+    fn is_asleep(&self) -> bool {
+        self.ticks_since_press > self.sleep_timeout as u32 * TICKS_PER_MINUTE
+    }
+
     fn tick(&mut self) -> Multi<Message> {
         self.ticks_since_press = self.ticks_since_press.saturating_add(1);
 
-        if self.ticks_since_press > 3 * 60 * 24 {
-            One(Message::Sleep)
+        let wpm = match self.wpm.tick() {
+            Some(wpm) if self.usb_connected => Two(Message::Wpm(wpm), Message::SecondaryWpm(wpm)),
+            _ => None,
+        };
+        if self.is_asleep() {
+            wpm.add(One(Message::Sleep))
         } else {
-            None
+            wpm
         }
     }
 
-    fn press(&mut self) -> Multi<Message> {
-        if self.ticks_since_press > 3 * 60 * 24 {
+    fn press(&mut self, i: u8) -> Multi<Message> {
+        // turning an encoder isn't typing
+        if (i as usize) < ROWS {
+            self.wpm.press();
+        }
+        if self.is_asleep() {
             self.ticks_since_press = 0;
             One(Message::Wake)
         } else {
@@ -42,6 +93,20 @@ impl Info {
     }
 }
 
+// ctrl, shift, alt and gui for either hand, upper case when locked
+fn one_shot_mods(status: one_shot::Status<Layer>) -> [u8; 4] {
+    let mut buffer = [b' '; 4];
+    for (i, c) in b"csag".iter().enumerate() {
+        let bits = 0x11 << i;
+        if status.locked_mods & bits != 0 {
+            buffer[i] = c.to_ascii_uppercase();
+        } else if status.mods & bits != 0 {
+            buffer[i] = *c;
+        }
+    }
+    buffer
+}
+
 const fn bool_to_string(b: bool) -> &'static str {
     match b {
         true => "true",
@@ -90,31 +155,78 @@ impl super::State for Info {
             .draw(display)
             .unwrap();
 
-        Text::new("layer:", Point::new(0, 26))
+        let mut buffer: [u8; 20] = [0; 20];
+
+        Text::new("wpm:", Point::new(0, 26))
             .into_styled(font_6x8)
             .draw(display)
             .unwrap();
+        Text::new(
+            self.shown_wpm.numtoa_str(10, &mut buffer),
+            Point::new(24, 26),
+        )
+        .into_styled(font_6x8)
+        .draw(display)
+        .unwrap();
+
         Text::new(self.current_layer.into(), Point::new(0, 39))
             .into_styled(font_6x8)
             .draw(display)
             .unwrap();
 
         if self.hand == Some(Hand::Left) {
-            Text::new("cmd:", Point::new(0, 52))
-                .into_styled(font_6x8)
-                .draw(display)
-                .unwrap();
-            let cmd = bool_to_string(self.cmd_held);
-            Text::new(cmd, Point::new(30, 52))
-                .into_styled(font_6x8)
-                .draw(display)
-                .unwrap();
-            Text::new("ctrl:", Point::new(0, 65))
+            if let Some(slot) = self.recording {
+                let rec = [b'r', b'e', b'c', b' ', b'1' + slot];
+                Text::new(core::str::from_utf8(&rec).unwrap_or(""), Point::new(0, 52))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+            }
+
+            let os = self.one_shot;
+            if os.mods != 0 || os.layer.is_some() {
+                Text::new("os:", Point::new(0, 65))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+                let mods = one_shot_mods(os);
+                Text::new(core::str::from_utf8(&mods).unwrap_or(""), Point::new(24, 65))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+                if let Some(layer) = os.layer {
+                    Text::new(layer.into(), Point::new(0, 78))
+                        .into_styled(font_6x8)
+                        .draw(display)
+                        .unwrap();
+                    if os.layer_locked {
+                        Text::new("*", Point::new(58, 78))
+                            .into_styled(font_6x8)
+                            .draw(display)
+                            .unwrap();
+                    }
+                }
+            }
+
+            if self.caps_word {
+                Text::new("CAPS", Point::new(0, 91))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+            }
+
+            if self.remapping {
+                Text::new("remap", Point::new(0, 104))
+                    .into_styled(font_6x8)
+                    .draw(display)
+                    .unwrap();
+            }
+
+            Text::new("enc:", Point::new(0, 117))
                 .into_styled(font_6x8)
                 .draw(display)
                 .unwrap();
-            let ctrl = bool_to_string(self.ctrl_held);
-            Text::new(ctrl, Point::new(36, 65))
+            Text::new(self.encoder_mode.into(), Point::new(24, 117))
                 .into_styled(font_6x8)
                 .draw(display)
                 .unwrap();
@@ -142,16 +254,31 @@ impl super::State for Info {
             }
             Message::MatrixKeyPress(i, j) => {
                 self.last_matrix = Some(Event::Press(i, j));
-                self.press().add(if !self.usb_connected {
-                    One(Message::SecondaryKeyPress(i, 13 - j))
+                self.press(i).add(if !self.usb_connected {
+                    One(match rotary::turn_at(i, j) {
+                        Some(d) => Message::SecondaryEncoderPress(Encoder::Right, d),
+                        None => Message::SecondaryKeyPress(i, 13 - j),
+                    })
                 } else {
                     None
                 })
             }
+            Message::SecondaryKeyPress(i, _) if (i as usize) < ROWS => {
+                self.wpm.press();
+                None
+            }
+            Message::Wpm(wpm) => {
+                self.shown_wpm = wpm;
+                None
+            }
+            Message::SecondaryWpm(wpm) => One(Message::Wpm(wpm)),
             Message::MatrixKeyRelease(i, j) => {
                 self.last_matrix = Some(Event::Release(i, j));
                 if !self.usb_connected {
-                    One(Message::SecondaryKeyRelease(i, 13 - j))
+                    One(match rotary::turn_at(i, j) {
+                        Some(d) => Message::SecondaryEncoderRelease(Encoder::Right, d),
+                        None => Message::SecondaryKeyRelease(i, 13 - j),
+                    })
                 } else {
                     None
                 }
@@ -164,22 +291,68 @@ impl super::State for Info {
                 self.current_layer = layer;
                 None
             }
-            Message::CmdHeld => {
-                self.cmd_held = true;
+            Message::Recording(slot) => {
+                self.recording = slot;
+                None
+            }
+            Message::CapsWord(active) => {
+                self.caps_word = active;
                 None
             }
-            Message::CmdReleased => {
-                self.cmd_held = false;
+            Message::OneShot(status) => {
+                self.one_shot = status;
                 None
             }
-            Message::CtrlHeld => {
-                self.ctrl_held = true;
+            Message::Remap(remapping) => {
+                self.remapping = remapping;
                 None
             }
-            Message::CtrlReleased => {
-                self.ctrl_held = false;
+            Message::EncoderMode(mode) => {
+                self.encoder_mode = mode;
+                One(Message::SaveSetting(Setting::EncoderMode(mode)))
+            }
+            Message::LoadSetting(Setting::EncoderMode(mode)) => {
+                self.encoder_mode = mode;
                 None
             }
+            // both halves keep the same keymap, either can be primary
+            Message::EditKey(edit) => One(Message::SecondaryEditKey(edit)),
+            Message::ResetKeymap => One(Message::SecondaryResetKeymap),
+            Message::DisplaySelect(s) | Message::SecondaryDisplaySelect(s)
+                if s != DisplayedState::Menu && s != DisplayedState::Leader =>
+            {
+                One(Message::SaveSetting(Setting::Display(s)))
+            }
+            Message::LoadSetting(Setting::Display(s)) => One(Message::DisplaySelect(s)),
+            Message::LoadSetting(Setting::SleepTimeout(minutes)) => {
+                self.sleep_timeout = minutes;
+                None
+            }
+            Message::AdjustSleep(by) => {
+                let minutes = (self.sleep_timeout as i16 + by as i16).max(1);
+                self.sleep_timeout = core::cmp::min(minutes as u8, MAX_SLEEP_TIMEOUT);
+                Two(
+                    Message::SaveSetting(Setting::SleepTimeout(self.sleep_timeout)),
+                    Message::SecondarySleepTimeout(self.sleep_timeout),
+                )
+            }
+            Message::SecondarySleepTimeout(minutes) => {
+                self.sleep_timeout = minutes;
+                One(Message::SaveSetting(Setting::SleepTimeout(minutes)))
+            }
+            Message::FactoryReset => {
+                self.sleep_timeout = SLEEP_TIMEOUT;
+                self.encoder_mode = encoder_mode::Mode::default();
+                Two(
+                    Message::DisplaySelect(DisplayedState::default()),
+                    Message::SecondaryFactoryReset,
+                )
+            }
+            Message::SecondaryFactoryReset => {
+                self.sleep_timeout = SLEEP_TIMEOUT;
+                self.encoder_mode = encoder_mode::Mode::default();
+                One(Message::DisplaySelect(DisplayedState::default()))
+            }
             Message::Ping => One(Message::Pong),
             Message::UpdateDisplay => self.tick(),
             _ => None,
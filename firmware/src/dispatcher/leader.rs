@@ -0,0 +1,90 @@
+use super::*;
+
+use embedded_graphics::{
+    fonts::{Font6x8, Text},
+    pixelcolor::BinaryColor,
+    style::TextStyleBuilder,
+};
+use heapless::{consts::U4, Vec};
+
+use crate::leader::MAX_LENGTH;
+use crate::multi::{Multi, Multi::*};
+
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Action {
+    Start,
+    Key(u8),
+    End,
+}
+
+#[derive(Default)]
+pub struct LeaderDisplay {
+    keys: Vec<u8, U4>,
+    last_display_state: DisplayedState,
+}
+
+fn label(key_code: u8) -> u8 {
+    match key_code {
+        0x04..=0x1d => b'a' + key_code - 0x04,
+        0x1e..=0x26 => b'1' + key_code - 0x1e,
+        0x27 => b'0',
+        _ => b'?',
+    }
+}
+
+impl State for LeaderDisplay {
+    type Messages = Multi<Message>;
+
+    fn handle_event(&mut self, message: Message) -> Self::Messages {
+        match message {
+            Message::Leader(Action::Start) => {
+                self.keys.clear();
+                One(Message::DisplaySelect(DisplayedState::Leader))
+            }
+            Message::Leader(Action::Key(k)) => {
+                self.keys.push(k).ok();
+                None
+            }
+            Message::Leader(Action::End) => One(Message::DisplaySelect(self.last_display_state)),
+            Message::DisplaySelect(s) if s != DisplayedState::Leader => {
+                self.last_display_state = s;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn write_to_display<DI, DSIZE>(&mut self, display: &mut GraphicsMode<DI, DSIZE>)
+    where
+        DSIZE: DisplaySize,
+        DI: WriteOnlyDataCommand,
+    {
+        display.clear();
+        let font_6x8 = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+
+        Text::new("leader", Point::zero())
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
+        let mut buffer = [b'_'; MAX_LENGTH * 2 - 1];
+        for (i, k) in self.keys.iter().enumerate() {
+            buffer[i * 2] = label(*k);
+        }
+        for i in (1..buffer.len()).step_by(2) {
+            buffer[i] = b' ';
+        }
+
+        Text::new(
+            core::str::from_utf8(&buffer).unwrap_or(""),
+            Point::new(0, 13),
+        )
+        .into_styled(font_6x8)
+        .draw(display)
+        .unwrap();
+
+        display.flush().unwrap();
+    }
+}
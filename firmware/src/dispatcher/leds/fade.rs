@@ -18,14 +18,8 @@ impl FadeAfterRelease {
     }
 
     pub fn key_release(&mut self, i: usize, j: usize) {
-        match i {
-            0..=2 => self.current.keys[i][j] = self.key.clone(),
-            3 => {
-                if j > 1 {
-                    self.current.thumb[j - 2] = self.key.clone()
-                }
-            }
-            _ => (),
+        if let Some(led) = self.current.key_mut(i, j) {
+            *led = self.key;
         }
     }
 }
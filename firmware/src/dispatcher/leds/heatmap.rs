@@ -0,0 +1,57 @@
+use super::*;
+
+use crate::dispatcher::stats::Presses;
+
+/// Colours each key on this half by how often it's pressed next to the
+/// most pressed one, from blue to red. Keys never pressed are left off.
+pub(super) struct Heatmap {
+    current: LEDMatrix,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap {
+            current: LEDMatrix::default(),
+        }
+    }
+
+    pub fn update(&mut self, presses: &Presses, right: bool) {
+        let count = |i: usize, j: usize| {
+            presses
+                .get(i)
+                .and_then(|r| r.get(layout_column(right, j)))
+                .copied()
+                .unwrap_or(0)
+        };
+        let mut most = 0;
+        for i in 0..=3 {
+            for j in 0..=6 {
+                if self.current.key_mut(i, j).is_some() {
+                    most = most.max(count(i, j));
+                }
+            }
+        }
+
+        for i in 0..=3 {
+            for j in 0..=6 {
+                let presses = count(i, j);
+                if let Some(led) = self.current.key_mut(i, j) {
+                    *led = match presses {
+                        0 => (0, 0, 0).into(),
+                        // 170 is blue on the wheel, 0 is red
+                        _ => {
+                            let heat = (presses as u64 * 170 / most as u64) as u8;
+                            wheel::Wheel::wheel(170 - heat).into()
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl LEDMode for Heatmap {
+    fn next_matrix(&mut self, _last: LEDMatrix) -> Option<LEDMatrix> {
+        Some(self.current)
+    }
+}
@@ -14,11 +14,20 @@ use embedded_graphics::{
     style::TextStyleBuilder,
 };
 
+use crate::custom_action::PkbAction;
+use crate::dispatcher::stats::Presses;
+use crate::keymap::{Layer, LAYER_COLOURS, LAYER_PROFILES};
+use crate::layer_profile::{self, Tint};
+use crate::multi::{Multi, Multi::*};
+
+use keyberon::action::Action as KeyAction;
+use keyberon::layout::Layers;
 use numtoa::NumToA;
 use smart_leds::RGB8;
 
 mod driver;
 mod fade;
+mod heatmap;
 mod off;
 mod solid;
 mod wheel;
@@ -35,6 +44,7 @@ pub enum Mode {
     Wheel,
     Solid,
     Fade,
+    Heatmap,
 }
 
 impl From<Mode> for &str {
@@ -44,6 +54,7 @@ impl From<Mode> for &str {
             Mode::Wheel => "wheel",
             Mode::Solid => "solid",
             Mode::Fade => "fade",
+            Mode::Heatmap => "heat",
         }
     }
 }
@@ -58,7 +69,15 @@ pub enum Action {
     IncrementBlue,
     DecrementBlue,
     Solid(solid::Solid),
+    AdjustBrightness(i8),
+    Brightness(u8),
+    // around the colour wheel, for the solid colour
+    AdjustHue(i8),
     Update,
+    Recording(bool),
+    CapsWord(bool),
+    // the held layer whose keys are lit, from the primary
+    LayerIndicator(Option<Layer>),
 }
 #[derive(Copy, Clone, Default)]
 struct LEDMatrix {
@@ -71,6 +90,27 @@ impl LEDMatrix {
     fn iter(self) -> Iter {
         Iter { matrix: self, i: 0 }
     }
+
+    // The LED under the switch at `(i, j)` of this half's own matrix.
+    fn key_mut(&mut self, i: usize, j: usize) -> Option<&mut RGB8> {
+        match (i, j) {
+            (0..=2, 0..=6) => Some(&mut self.keys[i][j]),
+            (3, 2..=6) => Some(&mut self.thumb[j - 2]),
+            _ => Option::None,
+        }
+    }
+
+    fn dimmed(mut self, brightness: u8) -> Self {
+        let dim = |c: u8| ((c as u16 * (brightness as u16 + 1)) >> 8) as u8;
+        let keys = self.keys.iter_mut().flatten();
+        for led in keys
+            .chain(self.thumb.iter_mut())
+            .chain(self.underglow.iter_mut())
+        {
+            *led = RGB8::new(dim(led.r), dim(led.g), dim(led.b));
+        }
+        self
+    }
 }
 
 impl IntoIterator for LEDMatrix {
@@ -117,6 +157,30 @@ impl Iterator for Iter {
     }
 }
 
+// This half's own column to the layout's.
+fn layout_column(right: bool, j: usize) -> usize {
+    if right {
+        13 - j
+    } else {
+        j
+    }
+}
+
+const MAX_BRIGHTNESS: u8 = 255;
+
+// underglow while a macro is being recorded
+const RECORDING: RGB8 = RGB8 {
+    r: 0x80,
+    g: 0,
+    b: 0,
+};
+// thumb keys while caps word is on
+const CAPS_WORD: RGB8 = RGB8 {
+    r: 0x80,
+    g: 0x80,
+    b: 0x80,
+};
+
 trait LEDMode {
     fn next_matrix(&mut self, last: LEDMatrix) -> Option<LEDMatrix>;
 }
@@ -126,10 +190,23 @@ pub struct LEDs {
     last: LEDMatrix,
     mode: Mode,
     solid_rgb: solid::Solid,
+    brightness: u8,
+    hue: u8,
+    right: bool,
+    // from the current layer's profile, over the chosen mode
+    profile_mode: Option<Mode>,
+    tint: Option<&'static Tint>,
+    // the keymap, to light the keys bound on `indicated`
+    layers: Option<Layers<PkbAction>>,
+    default_layer: usize,
+    indicated: Option<Layer>,
     off: off::Off,
     wheel: wheel::Wheel,
     fade: fade::FadeAfterRelease,
+    heatmap: heatmap::Heatmap,
     sleep: bool,
+    recording: bool,
+    caps_word: bool,
 }
 
 impl LEDs {
@@ -157,25 +234,101 @@ impl LEDs {
             last: LEDMatrix::default(),
             mode: Mode::Solid,
             solid_rgb: solid::Solid::new(),
+            brightness: MAX_BRIGHTNESS,
+            hue: 0,
+            right: false,
+            profile_mode: Option::None,
+            tint: Option::None,
+            layers: Option::None,
+            default_layer: 0,
+            indicated: Option::None,
             off: off::Off::new(),
             wheel: wheel::Wheel::new(),
             fade: fade::FadeAfterRelease::new(),
+            heatmap: heatmap::Heatmap::new(),
             sleep: false,
+            recording: false,
+            caps_word: false,
         }
     }
 
+    pub fn set_layers(&mut self, layers: Layers<PkbAction>) {
+        self.layers = Some(layers);
+    }
+
+    pub fn set_presses(&mut self, presses: &Presses) {
+        self.heatmap.update(presses, self.right);
+    }
+
     fn choose_mode(&mut self, mode: Mode) {
         self.mode = mode;
         self.update_leds();
     }
 
+    fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+        self.update_leds();
+    }
+
+    fn apply_profile(&mut self, layer: Layer) {
+        let profile = layer_profile::for_layer(LAYER_PROFILES, layer);
+        self.profile_mode = profile.and_then(|p| p.leds);
+        self.tint = profile.and_then(|p| p.tint.as_ref());
+        self.update_leds();
+    }
+
+    // Layout coordinates on this half to its own, the right half's columns
+    // are mirrored.
+    fn local(&self, (i, j): (u8, u8)) -> Option<(usize, usize)> {
+        match (self.right, j) {
+            (false, 0..=6) => Some((i as usize, j as usize)),
+            (true, 7..=13) => Some((i as usize, 13 - j as usize)),
+            _ => Option::None,
+        }
+    }
+
+    fn indicate(&mut self, layer: Option<Layer>) {
+        self.indicated = layer;
+        self.refresh_overlay();
+    }
+
+    // Lights the keys bound on the held layer in its colour, and dims the
+    // ones that fall through or do nothing.
+    fn layer_indicator(&self, matrix: &mut LEDMatrix) {
+        let (layer, layers) = match (self.indicated, self.layers) {
+            (Some(layer), Some(layers)) => (usize::from(layer), layers),
+            _ => return,
+        };
+        let keys = match layers.get(layer) {
+            Some(keys) => keys,
+            _ => return,
+        };
+        let colour = LAYER_COLOURS[layer].into();
+        for i in 0..=3 {
+            for j in 0..=6 {
+                let action = keys
+                    .get(i)
+                    .and_then(|r| r.get(layout_column(self.right, j)));
+                if let Some(led) = matrix.key_mut(i, j) {
+                    *led = match action {
+                        Some(KeyAction::Trans) | Some(KeyAction::NoOp) | Option::None => {
+                            RGB8::new(led.r / 8, led.g / 8, led.b / 8)
+                        }
+                        Some(_) => colour,
+                    };
+                }
+            }
+        }
+    }
+
     fn update_leds(&mut self) {
-        match (self.sleep, self.mode) {
+        match (self.sleep, self.profile_mode.unwrap_or(self.mode)) {
             (true, _) => (),
             (_, Mode::Off) => self.off(),
             (_, Mode::Solid) => self.solid(),
             (_, Mode::Wheel) => self.wheel(),
             (_, Mode::Fade) => self.fade(),
+            (_, Mode::Heatmap) => self.heatmap(),
         }
     }
 
@@ -205,16 +358,62 @@ impl LEDs {
         self.write_all(matrix);
     }
 
+    fn heatmap(&mut self) {
+        let matrix = self.heatmap.next_matrix(self.last);
+        self.write_all(matrix);
+    }
+
     fn write_all(&mut self, matrix: Option<LEDMatrix>) {
         if let Some(next) = matrix {
-            self.leds.write(next);
+            self.leds.write(self.overlay(next).dimmed(self.brightness));
             self.last = next;
         }
     }
+
+    fn overlay(&self, mut matrix: LEDMatrix) -> LEDMatrix {
+        self.layer_indicator(&mut matrix);
+        if let Some(tint) = self.tint {
+            for (i, j) in tint.keys.iter().filter_map(|k| self.local(*k)) {
+                if let Some(led) = matrix.key_mut(i, j) {
+                    *led = tint.colour.into();
+                }
+            }
+        }
+        if self.recording {
+            for led in matrix.underglow.iter_mut() {
+                *led = RECORDING;
+            }
+        }
+        if self.caps_word {
+            for led in matrix.thumb.iter_mut() {
+                *led = CAPS_WORD;
+            }
+        }
+        matrix
+    }
+
+    fn colour(&self) -> Setting {
+        let rgb = &self.solid_rgb;
+        Setting::Colour(rgb.red(), rgb.green(), rgb.blue())
+    }
+
+    fn solid_changed(&self) -> Multi<Message> {
+        Two(
+            Message::SecondaryLED(Action::Solid(self.solid_rgb)),
+            Message::SaveSetting(self.colour()),
+        )
+    }
+
+    fn refresh_overlay(&mut self) {
+        if !self.sleep {
+            self.leds
+                .write(self.overlay(self.last).dimmed(self.brightness));
+        }
+    }
 }
 
 impl State for LEDs {
-    type Messages = Option<Message>;
+    type Messages = Multi<Message>;
     #[inline]
     fn handle_event(&mut self, message: Message) -> Self::Messages {
         match message {
@@ -224,44 +423,129 @@ impl State for LEDs {
             }
             Message::LED(Action::SetMode(mode)) => {
                 self.choose_mode(mode);
-                Some(Message::SecondaryLED(Action::SetMode(mode)))
+                Two(
+                    Message::SecondaryLED(Action::SetMode(mode)),
+                    Message::SaveSetting(Setting::LedMode(mode)),
+                )
             }
             Message::LED(Action::IncrementRed) => {
                 self.solid_rgb.increment_red();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
             }
             Message::LED(Action::DecrementRed) => {
                 self.solid_rgb.decrement_red();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
             }
             Message::LED(Action::IncrementGreen) => {
                 self.solid_rgb.increment_green();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
             }
             Message::LED(Action::DecrementGreen) => {
                 self.solid_rgb.decrement_green();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
             }
             Message::LED(Action::IncrementBlue) => {
                 self.solid_rgb.increment_blue();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
             }
             Message::LED(Action::DecrementBlue) => {
                 self.solid_rgb.decrement_blue();
-                Some(Message::SecondaryLED(Action::Solid(self.solid_rgb.clone())))
+                self.solid_changed()
+            }
+            Message::LED(Action::AdjustBrightness(by)) => {
+                let brightness = self.brightness as i16 + by as i16;
+                self.set_brightness(brightness.max(0).min(MAX_BRIGHTNESS as i16) as u8);
+                Two(
+                    Message::SecondaryLED(Action::Brightness(self.brightness)),
+                    Message::SaveSetting(Setting::Brightness(self.brightness)),
+                )
+            }
+            Message::SecondaryLED(Action::Brightness(brightness)) => {
+                self.set_brightness(brightness);
+                One(Message::SaveSetting(Setting::Brightness(brightness)))
+            }
+            Message::LoadSetting(Setting::Brightness(brightness)) => {
+                self.set_brightness(brightness);
+                None
+            }
+            Message::LED(Action::AdjustHue(by)) => {
+                self.hue = self.hue.wrapping_add(by as u8);
+                self.solid_rgb.update(wheel::Wheel::wheel(self.hue));
+                self.solid_changed()
             }
             Message::SecondaryLED(Action::SetMode(mode)) => {
                 self.choose_mode(mode);
-                None
+                One(Message::SaveSetting(Setting::LedMode(mode)))
             }
             Message::SecondaryLED(Action::Solid(rgb)) => {
                 self.solid_rgb = rgb;
+                One(Message::SaveSetting(self.colour()))
+            }
+            Message::LoadSetting(Setting::LedMode(mode)) => {
+                self.choose_mode(mode);
+                None
+            }
+            Message::LoadSetting(Setting::Colour(r, g, b)) => {
+                self.solid_rgb.update((r, g, b));
+                self.update_leds();
+                None
+            }
+            Message::FactoryReset | Message::SecondaryFactoryReset => {
+                self.solid_rgb = solid::Solid::new();
+                self.brightness = MAX_BRIGHTNESS;
+                self.choose_mode(Mode::Solid);
+                None
+            }
+            Message::Recording(slot) => {
+                self.recording = slot.is_some();
+                self.refresh_overlay();
+                One(Message::SecondaryLED(Action::Recording(self.recording)))
+            }
+            Message::SecondaryLED(Action::Recording(recording)) => {
+                self.recording = recording;
+                self.refresh_overlay();
+                None
+            }
+            Message::CapsWord(active) => {
+                self.caps_word = active;
+                self.refresh_overlay();
+                One(Message::SecondaryLED(Action::CapsWord(active)))
+            }
+            Message::SecondaryLED(Action::CapsWord(active)) => {
+                self.caps_word = active;
+                self.refresh_overlay();
                 None
             }
             Message::LateInit => {
                 self.choose_mode(self.mode);
                 None
             }
+            Message::YouAreSecondary => {
+                self.right = true;
+                None
+            }
+            Message::CurrentLayer(layer) => {
+                self.apply_profile(layer);
+                let indicated = if usize::from(layer) != self.default_layer {
+                    Some(layer)
+                } else {
+                    Option::None
+                };
+                self.indicate(indicated);
+                One(Message::SecondaryLED(Action::LayerIndicator(indicated)))
+            }
+            Message::SecondaryCurrentLayer(layer) => {
+                self.apply_profile(layer);
+                None
+            }
+            Message::SecondaryLED(Action::LayerIndicator(layer)) => {
+                self.indicate(layer);
+                None
+            }
+            Message::SetDefaultLayer(l) => {
+                self.default_layer = l;
+                None
+            }
             Message::MatrixKeyRelease(i, j) => {
                 self.fade.key_release(i as usize, j as usize);
                 None
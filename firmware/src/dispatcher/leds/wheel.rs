@@ -7,7 +7,7 @@ impl Wheel {
         Wheel(0)
     }
 
-    fn wheel(mut wheel_pos: u8) -> (u8, u8, u8) {
+    pub(super) fn wheel(mut wheel_pos: u8) -> (u8, u8, u8) {
         wheel_pos = 255 - wheel_pos;
         if wheel_pos < 85 {
             return (255 - wheel_pos * 3, 0, wheel_pos * 3);
@@ -0,0 +1,95 @@
+use super::*;
+
+use embedded_graphics::{
+    fonts::{Font6x8, Text},
+    pixelcolor::BinaryColor,
+    style::TextStyleBuilder,
+};
+use keyberon::layout::Layers;
+
+use crate::custom_action::PkbAction;
+use crate::keymap::ROWS;
+use crate::legend;
+use crate::multi::{Multi, Multi::*};
+
+// Three legends of up to three characters to a line, a key row takes up to
+// three lines.
+const PER_LINE: usize = 3;
+const COLUMN_WIDTH: i32 = 23;
+const LINE_HEIGHT: i32 = 11;
+const ROW_HEIGHT: i32 = 35;
+
+/// The current layer's keys on this half, as a cheat sheet.
+#[derive(Default)]
+pub struct LegendDisplay {
+    layers: Option<Layers<PkbAction>>,
+    layer: Layer,
+    right: bool,
+}
+
+impl LegendDisplay {
+    pub fn set_layers(&mut self, layers: Layers<PkbAction>) {
+        self.layers = Some(layers);
+    }
+
+    // In layout coordinates, inner keys last on the left half and first on
+    // the right, like they're laid out. The thumb row has no outer keys.
+    fn columns(&self, i: usize) -> core::ops::RangeInclusive<usize> {
+        match (i == ROWS - 1, self.right) {
+            (false, false) => 0..=6,
+            (false, true) => 7..=13,
+            (true, false) => 2..=6,
+            (true, true) => 7..=11,
+        }
+    }
+}
+
+impl State for LegendDisplay {
+    type Messages = Multi<Message>;
+
+    fn handle_event(&mut self, message: Message) -> Self::Messages {
+        match message {
+            Message::YouAreSecondary => self.right = true,
+            Message::CurrentLayer(layer) | Message::SecondaryCurrentLayer(layer) => {
+                self.layer = layer
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn write_to_display<DI, DSIZE>(&mut self, display: &mut GraphicsMode<DI, DSIZE>)
+    where
+        DSIZE: DisplaySize,
+        DI: WriteOnlyDataCommand,
+    {
+        display.clear();
+        let font_6x8 = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let keys = self
+            .layers
+            .and_then(|layers| layers.get(usize::from(self.layer)));
+
+        if let Some(keys) = keys {
+            for i in 0..ROWS {
+                for (k, j) in self.columns(i).enumerate() {
+                    let action = match keys.get(i).and_then(|r| r.get(j)) {
+                        Some(action) => action,
+                        _ => continue,
+                    };
+                    let x = (k % PER_LINE) as i32 * COLUMN_WIDTH;
+                    let y = i as i32 * ROW_HEIGHT + (k / PER_LINE) as i32 * LINE_HEIGHT;
+
+                    Text::new(legend::of(action).as_str(), Point::new(x, y))
+                        .into_styled(font_6x8)
+                        .draw(display)
+                        .unwrap();
+                }
+            }
+        }
+
+        display.flush().unwrap();
+    }
+}
@@ -6,18 +6,24 @@ use embedded_graphics::{
     style::TextStyleBuilder,
 };
 
+use crate::debounce::{Action as DebounceAction, Strategy};
 use crate::dispatcher::leds::{Action, Mode};
 use crate::multi::{Multi, Multi::*};
+use crate::rotary::Direction;
+use crate::tap_hold::Action as TapHoldAction;
 
 #[rustfmt::skip]
 const MENU : &[&[MenuItem]] = &[
-    &[i("ping", Message::Ping), sm("display", 1), sm("leds", 5), sm("keymap", 4)],
-    &[sm("left", 2), sm("right", 3)],
-    &[i("info", Message::DisplaySelect(DisplayedState::Info)), i("bongo", Message::DisplaySelect(DisplayedState::Bongo)), i("leds", Message::DisplaySelect(DisplayedState::Leds))],
-    &[i("info", Message::SecondaryDisplaySelect(DisplayedState::Info)), i("bongo", Message::SecondaryDisplaySelect(DisplayedState::Bongo)), i("leds", Message::SecondaryDisplaySelect(DisplayedState::Leds))],
-    &[i("default", Message::SetDefaultLayer(0)), i("cs", Message::SetDefaultLayer(Layer::CS as usize))],
-    &[i("off", Message::LED(Action::SetMode(leds::Mode::Off))), smn("solid", 6, DisplayedState::Leds, Message::LED(Action::SetMode(Mode::Solid))), i("wheel", Message::LED(Action::SetMode(leds::Mode::Wheel))), i("fade", Message::LED(Action::SetMode(leds::Mode::Fade)))],
-    &[d("red", Message::LED(Action::DecrementRed), Message::LED(Action::IncrementRed)), d("green", Message::LED(Action::DecrementGreen), Message::LED(Action::IncrementGreen)), d("blue", Message::LED(Action::DecrementBlue), Message::LED(Action::IncrementBlue))]];
+    &[i("ping", Message::Ping), sm("display", 1), sm("leds", 5), sm("keymap", 4), sm("tap hold", 7), sm("debounce", 8), sm("diag", 9), i("reset", Message::FactoryReset)],
+    &[sm("left", 2), sm("right", 3), d("sleep", Message::AdjustSleep(-1), Message::AdjustSleep(1))],
+    &[i("info", Message::DisplaySelect(DisplayedState::Info)), i("bongo", Message::DisplaySelect(DisplayedState::Bongo)), i("leds", Message::DisplaySelect(DisplayedState::Leds)), i("stats", Message::DisplaySelect(DisplayedState::Stats)), i("legend", Message::DisplaySelect(DisplayedState::Legend))],
+    &[i("info", Message::SecondaryDisplaySelect(DisplayedState::Info)), i("bongo", Message::SecondaryDisplaySelect(DisplayedState::Bongo)), i("leds", Message::SecondaryDisplaySelect(DisplayedState::Leds)), i("stats", Message::SecondaryDisplaySelect(DisplayedState::Stats)), i("legend", Message::SecondaryDisplaySelect(DisplayedState::Legend))],
+    &[i("default", Message::SetDefaultLayer(0)), i("cs", Message::SetDefaultLayer(Layer::CS as usize)), i("remap", Message::Remap(true)), i("factory", Message::ResetKeymap)],
+    &[i("off", Message::LED(Action::SetMode(leds::Mode::Off))), smn("solid", 6, DisplayedState::Leds, Message::LED(Action::SetMode(Mode::Solid))), i("wheel", Message::LED(Action::SetMode(leds::Mode::Wheel))), i("fade", Message::LED(Action::SetMode(leds::Mode::Fade))), i("heat", Message::LED(Action::SetMode(leds::Mode::Heatmap)))],
+    &[d("red", Message::LED(Action::DecrementRed), Message::LED(Action::IncrementRed)), d("green", Message::LED(Action::DecrementGreen), Message::LED(Action::IncrementGreen)), d("blue", Message::LED(Action::DecrementBlue), Message::LED(Action::IncrementBlue))],
+    &[d("term", Message::TapHold(TapHoldAction::DecrementTappingTerm), Message::TapHold(TapHoldAction::IncrementTappingTerm)), d("quick", Message::TapHold(TapHoldAction::DecrementQuickTapTerm), Message::TapHold(TapHoldAction::IncrementQuickTapTerm)), i("permit", Message::TapHold(TapHoldAction::TogglePermissiveHold)), i("eager", Message::TapHold(TapHoldAction::ToggleHoldOnOtherKeyPress))],
+    &[i("eager", Message::Debounce(DebounceAction::Strategy(Strategy::EagerPress))), i("defer", Message::Debounce(DebounceAction::Strategy(Strategy::Defer))), i("global", Message::Debounce(DebounceAction::Strategy(Strategy::Global))), d("time", Message::Debounce(DebounceAction::DecrementTime), Message::Debounce(DebounceAction::IncrementTime))],
+    &[i("left", Message::DisplaySelect(DisplayedState::Diagnostics)), i("right", Message::SecondaryDisplaySelect(DisplayedState::Diagnostics)), i("dump", Message::DumpDiagnostics), i("clear", Message::ClearDiagnostics)]];
 
 #[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum MenuAction {
@@ -27,6 +33,8 @@ pub enum MenuAction {
     Close,
     Left,
     Right,
+    // an encoder, turning the highlighted dial or moving between items
+    Turn(Direction),
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -43,10 +51,13 @@ pub struct Menu {
     default_layer: usize,
     previous_menu: Multi<usize>,
     previous_item: Multi<usize>,
+    // a dial let go of with select, turns move on past it
+    passing: bool,
 }
 
 impl Menu {
     fn up(&mut self) -> Multi<Message> {
+        self.passing = false;
         if self.current_item > 0 {
             self.current_item -= 1;
         }
@@ -54,6 +65,7 @@ impl Menu {
     }
 
     fn down(&mut self) -> Multi<Message> {
+        self.passing = false;
         if self.current_item < MENU[self.current_menu].len() {
             self.current_item += 1;
         }
@@ -61,16 +73,39 @@ impl Menu {
     }
 
     fn left(&mut self) -> Multi<Message> {
-        match MENU[self.current_menu][self.current_item - 1].menu_type {
-            Type::Dial(left, _) => One(left),
-            _ => None,
+        match self.dial() {
+            Some((left, _)) => One(left),
+            Option::None => None,
         }
     }
 
     fn right(&mut self) -> Multi<Message> {
-        match MENU[self.current_menu][self.current_item - 1].menu_type {
-            Type::Dial(_, right) => One(right),
-            _ => None,
+        match self.dial() {
+            Some((_, right)) => One(right),
+            Option::None => None,
+        }
+    }
+
+    fn turn(&mut self, d: Direction) -> Multi<Message> {
+        match (self.is_adjusting(), d) {
+            (true, Direction::CW) => self.right(),
+            (true, Direction::ACW) => self.left(),
+            (false, Direction::CW) => self.down(),
+            (false, Direction::ACW) => self.up(),
+        }
+    }
+
+    // Turning adjusts the highlighted dial.
+    fn is_adjusting(&self) -> bool {
+        self.dial().is_some() && !self.passing
+    }
+
+    // the current item, if it is a dial, "back" isn't
+    fn dial(&self) -> Option<(Message, Message)> {
+        let item = MENU[self.current_menu].get(self.current_item.checked_sub(1)?)?;
+        match item.menu_type {
+            Type::Dial(left, right) => Some((left, right)),
+            _ => Option::None,
         }
     }
 
@@ -99,7 +134,10 @@ impl Menu {
                         self.current_item = 0;
                         One(Message::SecondaryMenu(SecondaryMenuAction::Open(secondary)))
                     }
-                    _ => None,
+                    Type::Dial(_, _) => {
+                        self.passing = !self.passing;
+                        None
+                    }
                 };
                 messages.add(item.message)
             }
@@ -109,6 +147,7 @@ impl Menu {
     fn close(&mut self) -> Multi<Message> {
         self.previous_menu = None;
         self.previous_item = None;
+        self.passing = false;
         self.current_menu = 0;
         self.current_item = 0;
         Three(
@@ -119,7 +158,7 @@ impl Menu {
     }
 
     fn last_display_state(&mut self, s: DisplayedState) -> Multi<Message> {
-        if s != DisplayedState::Menu {
+        if s != DisplayedState::Menu && s != DisplayedState::Leader {
             self.last_display_state = s;
         }
         None
@@ -136,6 +175,7 @@ impl State for Menu {
             Message::Menu(MenuAction::Down) => self.down(),
             Message::Menu(MenuAction::Left) => self.left(),
             Message::Menu(MenuAction::Right) => self.right(),
+            Message::Menu(MenuAction::Turn(d)) => self.turn(d),
             Message::Menu(MenuAction::Select) => self.select(),
             Message::Menu(MenuAction::Close) => self.close(),
             Message::DisplaySelect(s) => self.last_display_state(s),
@@ -148,8 +188,19 @@ impl State for Menu {
                 None
             }
             Message::SetDefaultLayer(l) => {
+                let changed = l != self.default_layer;
                 self.default_layer = l;
-                None
+                if changed {
+                    One(Message::SaveSetting(Setting::DefaultLayer(l as u8)))
+                } else {
+                    None
+                }
+            }
+            Message::LoadSetting(Setting::DefaultLayer(l)) => {
+                One(Message::SetDefaultLayer(l as usize))
+            }
+            Message::FactoryReset | Message::SecondaryFactoryReset => {
+                One(Message::SetDefaultLayer(0))
             }
             _ => None,
         }
@@ -178,7 +229,8 @@ impl State for Menu {
                 .unwrap();
         }
 
-        Text::new("-", Point::new(0, (self.current_item * 13) as i32))
+        let marker = if self.is_adjusting() { "*" } else { "-" };
+        Text::new(marker, Point::new(0, (self.current_item * 13) as i32))
             .into_styled(font_6x8)
             .draw(display)
             .unwrap();
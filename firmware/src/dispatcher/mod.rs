@@ -1,23 +1,46 @@
 use serde::{Deserialize, Serialize};
 
 use embedded_graphics::prelude::*;
+use keyberon::layout::Layers;
 use ssd1306::{displaysize::DisplaySize, mode::GraphicsMode, prelude::*};
 
-use crate::keymap::Layer;
+use crate::custom_action::PkbAction;
+use crate::debounce;
+use crate::diagnostics::Report;
+use crate::dynamic_keymap::KeyEdit;
+use crate::encoder_mode;
+use crate::flash::Flash;
+use crate::keymap::{Layer, LAYER_PROFILES};
+use crate::layer_profile;
+use crate::one_shot;
+use crate::rotary::{Direction, Encoder};
+use crate::settings;
+use crate::store::{self, Store};
+use crate::tap_hold;
 
 mod bongo;
+mod diagnostics;
 pub mod display;
 mod info;
+pub mod leader;
 pub mod leds;
+mod legend;
 pub mod menu;
+pub mod stats;
 
 pub struct Dispatcher {
     oled: display::OLED,
     displayed_state: DisplayedState,
+    // what the current layer's profile shows instead
+    profile_display: Option<DisplayedState>,
     info: info::Info,
     menu: menu::Menu,
     leds: leds::LEDs,
     bongo: bongo::Bongo,
+    leader: leader::LeaderDisplay,
+    stats: stats::Stats,
+    legend: legend::LegendDisplay,
+    diagnostics: diagnostics::Diagnostics,
 }
 
 macro_rules! display {
@@ -42,39 +65,91 @@ impl Dispatcher {
         Dispatcher {
             oled,
             displayed_state: DisplayedState::default(),
+            profile_display: None,
             info: info::Info::default(),
             menu: menu::Menu::default(),
             leds,
             bongo: bongo::Bongo::default(),
+            leader: leader::LeaderDisplay::default(),
+            stats: stats::Stats::default(),
+            legend: legend::LegendDisplay::default(),
+            diagnostics: diagnostics::Diagnostics::default(),
         }
     }
 
+    /// The keymap has to be set again whenever it's edited.
+    pub fn set_layers(&mut self, layers: Layers<PkbAction>) {
+        self.leds.set_layers(layers);
+        self.legend.set_layers(layers);
+    }
+
+    pub fn load_stats(&mut self, store: &Store<Flash>) {
+        self.stats.load(store);
+        self.leds.set_presses(&self.stats.presses());
+    }
+
+    pub fn save_stats(&mut self, store: &mut Store<Flash>) -> Result<(), store::Error> {
+        self.stats.save(store)
+    }
+
     #[inline]
     pub fn dispatch(&mut self, message: Message) -> impl Iterator<Item = Message> {
         let messages = None.into_iter();
 
-        dispatch!(messages, message, self.oled, self.info, self.leds, self.menu, self.bongo);
+        dispatch!(
+            messages,
+            message,
+            self.oled,
+            self.info,
+            self.leds,
+            self.menu,
+            self.bongo,
+            self.leader,
+            self.stats,
+            self.legend,
+            self.diagnostics
+        );
 
         match message {
             Message::DisplaySelect(d) => self.displayed_state = d,
             Message::SecondaryDisplaySelect(d) => self.displayed_state = d,
+            Message::CurrentLayer(l) | Message::SecondaryCurrentLayer(l) => {
+                self.profile_display =
+                    layer_profile::for_layer(LAYER_PROFILES, l).and_then(|p| p.display);
+            }
+            Message::MatrixKeyPress(_, _)
+            | Message::SecondaryKeyPress(_, _)
+            | Message::YouAreSecondary => self.leds.set_presses(&self.stats.presses()),
             _ => (),
         }
         messages
     }
 
     pub fn update_display(&mut self) {
+        let displayed_state = match (self.displayed_state, self.profile_display) {
+            (DisplayedState::Menu, _) | (DisplayedState::Leader, _) | (_, None) => {
+                self.displayed_state
+            }
+            (_, Some(d)) => d,
+        };
         display!(
-            self.displayed_state,
+            displayed_state,
             self.oled,
             (DisplayedState::Info, &mut self.info),
             (DisplayedState::Menu, &mut self.menu),
             (DisplayedState::Bongo, &mut self.bongo),
-            (DisplayedState::Leds, &mut self.leds)
+            (DisplayedState::Leds, &mut self.leds),
+            (DisplayedState::Leader, &mut self.leader),
+            (DisplayedState::Stats, &mut self.stats),
+            (DisplayedState::Legend, &mut self.legend),
+            (DisplayedState::Diagnostics, &mut self.diagnostics)
         );
     }
 }
 
+/// What each half keeps across power cycles.
+pub type Setting = settings::Setting<leds::Mode, DisplayedState, encoder_mode::Mode>;
+
 #[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Message {
     LateInit,
@@ -88,12 +163,11 @@ pub enum Message {
     MatrixKeyRelease(u8, u8),
     SecondaryKeyPress(u8, u8),
     SecondaryKeyRelease(u8, u8),
+    SecondaryEncoderPress(Encoder, Direction),
+    SecondaryEncoderRelease(Encoder, Direction),
+    EncoderMode(encoder_mode::Mode),
     Ping,
     Pong,
-    CmdHeld,
-    CmdReleased,
-    CtrlHeld,
-    CtrlReleased,
     CurrentLayer(Layer),
     SecondaryCurrentLayer(Layer),
     DisplaySelect(DisplayedState),
@@ -101,9 +175,36 @@ pub enum Message {
     Menu(menu::MenuAction),
     SecondaryMenu(menu::SecondaryMenuAction),
     SetDefaultLayer(usize),
-    Bongo,
+    TapHold(tap_hold::Action),
+    Debounce(debounce::Action),
+    SecondaryDebounce(debounce::Config),
+    Leader(leader::Action),
+    Recording(Option<u8>),
+    OneShot(one_shot::Status<Layer>),
+    CapsWord(bool),
+    Remap(bool),
+    EditKey(KeyEdit),
+    SecondaryEditKey(KeyEdit),
+    ResetKeymap,
+    SecondaryResetKeymap,
+    LoadSetting(Setting),
+    SaveSetting(Setting),
+    SaveStats,
+    Diagnostic(Report),
+    DumpDiagnostics,
+    SecondaryDumpDiagnostics,
+    ClearDiagnostics,
+    SecondaryClearDiagnostics,
+    FactoryReset,
+    SecondaryFactoryReset,
+    AdjustSleep(i8),
+    SecondarySleepTimeout(u8),
+    Wpm(u8),
+    SecondaryWpm(u8),
     LED(leds::Action),
     SecondaryLED(leds::Action),
+    // a few minutes without a key press, see `stats`
+    Idle,
     Sleep,
     Wake,
 }
@@ -119,11 +220,20 @@ impl Message {
             Message::YouAreSecondary
             | Message::SecondaryKeyPress(_, _)
             | Message::SecondaryKeyRelease(_, _)
+            | Message::SecondaryEncoderPress(_, _)
+            | Message::SecondaryEncoderRelease(_, _)
             | Message::SecondaryDisplaySelect(_)
             | Message::SecondaryCurrentLayer(_)
             | Message::SecondaryLED(_)
             | Message::SecondaryMenu(_)
-            | Message::Bongo
+            | Message::SecondaryEditKey(_)
+            | Message::SecondaryResetKeymap
+            | Message::SecondaryFactoryReset
+            | Message::SecondaryDumpDiagnostics
+            | Message::SecondaryClearDiagnostics
+            | Message::SecondarySleepTimeout(_)
+            | Message::SecondaryDebounce(_)
+            | Message::SecondaryWpm(_)
             | Message::Pong => MessageType::Remote(self),
             _ => MessageType::Local(self),
         }
@@ -136,6 +246,10 @@ pub enum DisplayedState {
     Menu,
     Bongo,
     Leds,
+    Leader,
+    Stats,
+    Legend,
+    Diagnostics,
 }
 
 impl Default for DisplayedState {
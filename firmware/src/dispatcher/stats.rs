@@ -0,0 +1,233 @@
+use super::*;
+
+use embedded_graphics::{
+    fonts::{Font6x8, Text},
+    pixelcolor::BinaryColor,
+    style::TextStyleBuilder,
+};
+
+use crate::flash::Flash;
+use crate::keymap::{COLUMNS, LAYER_COUNT, ROWS};
+use crate::multi::{Multi, Multi::*};
+use crate::store::{self, key, Store};
+
+use numtoa::NumToA;
+
+/// Presses of each key in layout coordinates.
+pub type Presses = [[u32; COLUMNS]; ROWS];
+
+// Bump this when the counts are stored differently.
+const VERSION: u8 = 1;
+const RECORD: usize = ROWS * COLUMNS * 4;
+
+// Display updates, 24 a second, without a key press before the keyboard
+// is idle. All the counts are written at once, only then, so they don't
+// wear the flash.
+const IDLE: u32 = 24 * 60 * 5;
+
+// keys listed on the screen
+const TOP: usize = 6;
+
+/// Counts every key press by the layer it was pressed on. Each half counts
+/// its own keys, the primary also counts the ones the secondary sends over,
+/// so its counts cover the whole board. They're saved after a few minutes
+/// without a key press, or when the keyboard goes to sleep.
+pub struct Stats {
+    presses: [Presses; LAYER_COUNT],
+    layer: Layer,
+    right: bool,
+    unsaved: bool,
+    ticks_since_press: u32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            presses: [[[0; COLUMNS]; ROWS]; LAYER_COUNT],
+            layer: Layer::default(),
+            right: false,
+            unsaved: false,
+            ticks_since_press: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// On every layer together.
+    pub fn presses(&self) -> Presses {
+        let mut total = [[0; COLUMNS]; ROWS];
+        for layer in self.presses.iter() {
+            for (i, row) in layer.iter().enumerate() {
+                for (j, count) in row.iter().enumerate() {
+                    total[i][j] = total[i][j].saturating_add(*count);
+                }
+            }
+        }
+        total
+    }
+
+    pub fn load(&mut self, store: &Store<Flash>) {
+        for (l, layer) in self.presses.iter_mut().enumerate() {
+            if let Some((VERSION, bytes)) = store.get(key::STATS + l as u8) {
+                if bytes.len() != RECORD {
+                    continue;
+                }
+                let counts = layer.iter_mut().flatten();
+                for (count, chunk) in counts.zip(bytes.chunks_exact(4)) {
+                    *count = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+        }
+    }
+
+    pub fn save(&mut self, store: &mut Store<Flash>) -> Result<(), store::Error> {
+        if !self.unsaved {
+            return Ok(());
+        }
+
+        let mut bytes = [0; RECORD];
+        for (l, layer) in self.presses.iter().enumerate() {
+            let counts = layer.iter().flatten();
+            for (count, chunk) in counts.zip(bytes.chunks_exact_mut(4)) {
+                chunk.copy_from_slice(&count.to_le_bytes());
+            }
+            store.set(key::STATS + l as u8, VERSION, &bytes)?;
+        }
+        self.unsaved = false;
+        Ok(())
+    }
+
+    fn press(&mut self, i: u8, j: u8) {
+        let layer = usize::from(self.layer);
+        let count = self
+            .presses
+            .get_mut(layer)
+            .and_then(|l| l.get_mut(i as usize))
+            .and_then(|r| r.get_mut(j as usize));
+        if let Some(count) = count {
+            *count = count.saturating_add(1);
+            self.unsaved = true;
+        }
+        self.ticks_since_press = 0;
+    }
+
+    fn tick(&mut self) -> Multi<Message> {
+        self.ticks_since_press = self.ticks_since_press.saturating_add(1);
+        if self.ticks_since_press == IDLE {
+            One(Message::Idle)
+        } else {
+            None
+        }
+    }
+
+    // The most pressed keys on the current layer, most first.
+    fn top(&self) -> [(u32, u8, u8); TOP] {
+        let mut top = [(0, 0, 0); TOP];
+        let layer = match self.presses.get(usize::from(self.layer)) {
+            Some(layer) => layer,
+            _ => return top,
+        };
+        for (i, row) in layer.iter().enumerate() {
+            for (j, count) in row.iter().enumerate() {
+                if let Some(k) = top.iter().position(|(c, _, _)| count > c) {
+                    for n in (k + 1..TOP).rev() {
+                        top[n] = top[n - 1];
+                    }
+                    top[k] = (*count, i as u8, j as u8);
+                }
+            }
+        }
+        top
+    }
+}
+
+impl State for Stats {
+    type Messages = Multi<Message>;
+
+    fn handle_event(&mut self, message: Message) -> Self::Messages {
+        match message {
+            Message::UpdateDisplay => self.tick(),
+            Message::YouAreSecondary => {
+                self.right = true;
+                None
+            }
+            Message::CurrentLayer(layer) | Message::SecondaryCurrentLayer(layer) => {
+                self.layer = layer;
+                None
+            }
+            Message::MatrixKeyPress(i, j) => {
+                self.press(i, if self.right { 13 - j } else { j });
+                None
+            }
+            Message::SecondaryKeyPress(i, j) => {
+                self.press(i, j);
+                None
+            }
+            Message::Idle | Message::Sleep if self.unsaved => One(Message::SaveStats),
+            _ => None,
+        }
+    }
+
+    fn write_to_display<DI, DSIZE>(&mut self, display: &mut GraphicsMode<DI, DSIZE>)
+    where
+        DSIZE: DisplaySize,
+        DI: WriteOnlyDataCommand,
+    {
+        display.clear();
+        let font_6x8 = TextStyleBuilder::new(Font6x8)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let mut buffer: [u8; 20] = [0; 20];
+
+        Text::new("presses", Point::zero())
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
+        let total = self
+            .presses()
+            .iter()
+            .flatten()
+            .fold(0u32, |t, c| t.saturating_add(*c));
+
+        Text::new(total.numtoa_str(10, &mut buffer), Point::new(0, 13))
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
+        Text::new(self.layer.into(), Point::new(0, 26))
+            .into_styled(font_6x8)
+            .draw(display)
+            .unwrap();
+
+        for (n, (count, i, j)) in self.top().iter().enumerate() {
+            if *count == 0 {
+                break;
+            }
+            let y = 39 + 13 * n as i32;
+
+            Text::new(i.numtoa_str(10, &mut buffer), Point::new(0, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+
+            Text::new(",", Point::new(6, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+
+            Text::new(j.numtoa_str(10, &mut buffer), Point::new(12, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+
+            Text::new(count.numtoa_str(10, &mut buffer), Point::new(28, y))
+                .into_styled(font_6x8)
+                .draw(display)
+                .unwrap();
+        }
+
+        display.flush().unwrap();
+    }
+}
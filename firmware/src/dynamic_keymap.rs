@@ -0,0 +1,253 @@
+use heapless::{consts::U128, Vec};
+use keyberon::action::{k, Action, Action::NoOp};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{Layers, Layout};
+use serde::{Deserialize, Serialize};
+
+use crate::custom_action::PkbAction;
+use crate::flash::Flash;
+use crate::keymap::{COLUMNS, LAYERS, LAYER_COUNT, ROWS};
+use crate::store::{self, key, Store};
+
+// The edits are kept in the store as `[layer, i << 4 | j, key code]` each.
+const VERSION: u8 = 1;
+const EDIT: usize = 3;
+
+/// The key at `(i, j)` on `layer` types `key_code` instead, 0 puts back the
+/// key from `LAYERS`.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeyEdit {
+    pub layer: u8,
+    pub i: u8,
+    pub j: u8,
+    pub key_code: u8,
+}
+
+impl KeyEdit {
+    fn to_bytes(self) -> [u8; EDIT] {
+        [self.layer, (self.i << 4) | self.j, self.key_code]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        KeyEdit {
+            layer: bytes[0],
+            i: bytes[1] >> 4,
+            j: bytes[1] & 0x0f,
+            key_code: bytes[2],
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        (self.layer as usize) < LAYER_COUNT
+            && (self.i as usize) < ROWS
+            && (self.j as usize) < COLUMNS
+            && (self.key_code == 0 || key_code(self.key_code).is_some())
+    }
+
+    fn is_at(&self, other: &KeyEdit) -> bool {
+        (self.layer, self.i, self.j) == (other.layer, other.i, other.j)
+    }
+}
+
+// The key codes an edit can set, keys and then modifiers.
+#[rustfmt::skip]
+static KEY_CODES: &[KeyCode] = {
+    use KeyCode::*;
+    &[
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9, Kb0,
+        Enter, Escape, BSpace, Tab, Space, Minus, Equal, LBracket, RBracket, Bslash,
+        NonUsHash, SColon, Quote, Grave, Comma, Dot, Slash, CapsLock,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        PScreen, ScrollLock, Pause, Insert, Home, PgUp, Delete, End, PgDown,
+        Right, Left, Down, Up, NumLock,
+        KpSlash, KpAsterisk, KpMinus, KpPlus, KpEnter,
+        Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9, Kp0, KpDot,
+        NonUsBslash, Application, Power, KpEqual,
+        F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24,
+        Execute, Help, Menu, Select, Stop, Again, Undo, Cut, Copy, Paste, Find,
+        Mute, VolUp, VolDown, LockingCapsLock, LockingNumLock, LockingScrollLock,
+        KpComma, KpEqualSign,
+        Intl1, Intl2, Intl3, Intl4, Intl5, Intl6, Intl7, Intl8, Intl9,
+        Lang1, Lang2, Lang3, Lang4, Lang5, Lang6, Lang7, Lang8, Lang9,
+        AltErase, SysReq, Cancel, Clear, Prior, Return, Separator, Out, Oper,
+        ClearAgain, CrSel, ExSel,
+        LCtrl, LShift, LAlt, LGui, RCtrl, RShift, RAlt, RGui,
+    ]
+};
+
+fn key_code(byte: u8) -> Option<KeyCode> {
+    KEY_CODES.iter().copied().find(|kc| *kc as u8 == byte)
+}
+
+// `LAYER_ROW` and `ENCODER_ROW` follow the switches.
+const ALL_ROWS: usize = ROWS + 2;
+
+type Keys = [[Action<PkbAction>; COLUMNS]; ALL_ROWS];
+
+/// A copy of `LAYERS` for the layout to read, `init` makes the two a
+/// `DynamicKeymap` is built on.
+// `rows` and `layers` are the slices the layout reads, pointing into `keys`.
+pub struct Buffer {
+    keys: [Keys; LAYER_COUNT],
+    rows: [[&'static [Action<PkbAction>]; ALL_ROWS]; LAYER_COUNT],
+    layers: [&'static [&'static [Action<PkbAction>]]; LAYER_COUNT],
+}
+
+impl Buffer {
+    pub const EMPTY: Buffer = Buffer {
+        keys: [[[NoOp; COLUMNS]; ALL_ROWS]; LAYER_COUNT],
+        rows: [[&[]; ALL_ROWS]; LAYER_COUNT],
+        layers: [&[]; LAYER_COUNT],
+    };
+
+    // Rewrites the whole copy, the slices into it are only made once the
+    // keys are written.
+    fn fill(&'static mut self, edits: &[KeyEdit]) -> Layers<PkbAction> {
+        let Buffer { keys, rows, layers } = self;
+
+        for (keys, layer) in keys.iter_mut().zip(LAYERS.iter()) {
+            for (row, actions) in keys.iter_mut().zip(layer.iter()) {
+                for (key, action) in row.iter_mut().zip(actions.iter()) {
+                    *key = *action;
+                }
+            }
+        }
+        for edit in edits.iter() {
+            if let Some(kc) = key_code(edit.key_code) {
+                keys[edit.layer as usize][edit.i as usize][edit.j as usize] = k(kc);
+            }
+        }
+
+        let keys: &'static [Keys; LAYER_COUNT] = keys;
+        for (rows, keys) in rows.iter_mut().zip(keys.iter()) {
+            for (row, actions) in rows.iter_mut().zip(keys.iter()) {
+                *row = &actions[..];
+            }
+        }
+        let rows: &'static [[&'static [Action<PkbAction>]; ALL_ROWS]; LAYER_COUNT] = rows;
+        for (layer, rows) in layers.iter_mut().zip(rows.iter()) {
+            *layer = &rows[..];
+        }
+        &layers[..]
+    }
+}
+
+/// The keymap the layout runs on, `LAYERS` with the edits saved in flash.
+///
+/// There are two copies, an edit rebuilds the one the layout isn't using
+/// and hands the layers in it over, so the copy a layout reads from is never
+/// written to. The layout is given them by the edit, whatever else holds on
+/// to `layers()`, the dispatcher, has to be given them before the next edit.
+pub struct DynamicKeymap {
+    // Pointers, a `&mut` to the copy in use would alias the layers read
+    // from it.
+    buffers: [*mut Buffer; 2],
+    active: usize,
+    layers: Layers<PkbAction>,
+    edits: Vec<KeyEdit, U128>,
+    unsaved: bool,
+}
+
+// Nothing but the keymap reaches the copies, they go where it goes.
+unsafe impl Send for DynamicKeymap {}
+
+impl DynamicKeymap {
+    pub fn new(buffers: &'static mut [Buffer; 2]) -> Self {
+        let [first, second] = buffers;
+        let mut keymap = DynamicKeymap {
+            buffers: [first, second],
+            active: 0,
+            layers: &[],
+            edits: Vec::new(),
+            unsaved: false,
+        };
+        keymap.fill(0);
+        keymap
+    }
+
+    pub fn layers(&self) -> Layers<PkbAction> {
+        self.layers
+    }
+
+    /// Returns the new layers, or `None` if the edit is out of range or too
+    /// many keys are edited. The layout starts over on them: keys held
+    /// during the edit are let go of, and their releases do nothing.
+    pub fn edit(
+        &mut self,
+        layout: &mut Layout<PkbAction>,
+        edit: KeyEdit,
+    ) -> Option<Layers<PkbAction>> {
+        if !self.apply(edit) {
+            return None;
+        }
+        self.unsaved = true;
+        Some(self.reload(layout))
+    }
+
+    /// Goes back to `LAYERS`, like an edit.
+    pub fn reset(&mut self, layout: &mut Layout<PkbAction>) -> Layers<PkbAction> {
+        self.edits.clear();
+        self.unsaved = true;
+        self.reload(layout)
+    }
+
+    pub fn load(&mut self, store: &Store<Flash>) {
+        if let Some((VERSION, edits)) = store.get(key::KEYMAP) {
+            for bytes in edits.chunks_exact(EDIT) {
+                self.apply(KeyEdit::from_bytes(bytes));
+            }
+        }
+        // nothing reads the keymap before it is loaded
+        self.fill(self.active);
+    }
+
+    /// The store appends the edits, and only lets go of the keymap saved
+    /// before once they are completely written.
+    pub fn save(&mut self, store: &mut Store<Flash>) -> Result<(), store::Error> {
+        if !self.unsaved {
+            return Ok(());
+        }
+
+        let mut bytes = [0; 128 * EDIT];
+        for (edit, chunk) in self.edits.iter().zip(bytes.chunks_exact_mut(EDIT)) {
+            chunk.copy_from_slice(&edit.to_bytes());
+        }
+        store.set(key::KEYMAP, VERSION, &bytes[..self.edits.len() * EDIT])?;
+        self.unsaved = false;
+        Ok(())
+    }
+
+    fn apply(&mut self, edit: KeyEdit) -> bool {
+        if !edit.is_valid() {
+            return false;
+        }
+        match self.edits.iter().position(|e| e.is_at(&edit)) {
+            Some(k) if edit.key_code == 0 => {
+                self.edits.swap_remove(k);
+                true
+            }
+            Some(k) => {
+                self.edits[k] = edit;
+                true
+            }
+            None if edit.key_code == 0 => true,
+            None => self.edits.push(edit).is_ok(),
+        }
+    }
+
+    // Only the copy nothing reads from, or before anything reads the active
+    // one, may be filled.
+    fn fill(&mut self, buffer: usize) {
+        self.active = buffer;
+        // the layers in this copy were handed out before the last edit, the
+        // layout and the dispatcher were given the other copy's by it
+        self.layers = unsafe { &mut *self.buffers[buffer] }.fill(&self.edits);
+    }
+
+    fn reload(&mut self, layout: &mut Layout<PkbAction>) -> Layers<PkbAction> {
+        self.fill(1 - self.active);
+        *layout = Layout::new(self.layers);
+        self.layers
+    }
+}
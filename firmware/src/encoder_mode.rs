@@ -0,0 +1,73 @@
+use keyberon::key_code::KeyCode::{self, *};
+use serde::{Deserialize, Serialize};
+
+use crate::dispatcher::{leds, Message};
+use crate::quadrature::Direction;
+
+// LED brightness out of 255, and position on the colour wheel, per step.
+const BRIGHTNESS_STEP: i8 = 16;
+const HUE_STEP: i8 = 4;
+
+/// What turning an encoder bound to `ENC_CW` and `ENC_CCW` does, `ENC_MODE`
+/// goes to the next one. While the menu is shown turns move through it, or
+/// adjust the highlighted dial, whatever the mode.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Mode {
+    Volume,
+    Scroll,
+    Brightness,
+    Hue,
+    Undo,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Volume
+    }
+}
+
+impl Mode {
+    pub fn next(self) -> Self {
+        match self {
+            Mode::Volume => Mode::Scroll,
+            Mode::Scroll => Mode::Brightness,
+            Mode::Brightness => Mode::Hue,
+            Mode::Hue => Mode::Undo,
+            Mode::Undo => Mode::Volume,
+        }
+    }
+}
+
+impl From<Mode> for &str {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Volume => "volume",
+            Mode::Scroll => "scroll",
+            Mode::Brightness => "bright",
+            Mode::Hue => "hue",
+            Mode::Undo => "undo",
+        }
+    }
+}
+
+pub enum Output {
+    Keys(&'static [KeyCode]),
+    Message(Message),
+}
+
+pub fn turn(mode: Mode, d: Direction) -> Output {
+    let led = |action| Output::Message(Message::LED(action));
+    match (mode, d) {
+        (Mode::Volume, Direction::CW) => Output::Keys(&[VolUp]),
+        (Mode::Volume, Direction::ACW) => Output::Keys(&[VolDown]),
+        (Mode::Scroll, Direction::CW) => Output::Keys(&[PgDown]),
+        (Mode::Scroll, Direction::ACW) => Output::Keys(&[PgUp]),
+        (Mode::Brightness, Direction::CW) => led(leds::Action::AdjustBrightness(BRIGHTNESS_STEP)),
+        (Mode::Brightness, Direction::ACW) => led(leds::Action::AdjustBrightness(-BRIGHTNESS_STEP)),
+        (Mode::Hue, Direction::CW) => led(leds::Action::AdjustHue(HUE_STEP)),
+        (Mode::Hue, Direction::ACW) => led(leds::Action::AdjustHue(-HUE_STEP)),
+        // redo, undo
+        (Mode::Undo, Direction::CW) => Output::Keys(&[LGui, LShift, Z]),
+        (Mode::Undo, Direction::ACW) => Output::Keys(&[LGui, Z]),
+    }
+}
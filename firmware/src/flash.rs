@@ -0,0 +1,131 @@
+use crate::hal::stm32::FLASH;
+use crate::store::{self, Storage};
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+// PGSERR | PGPERR | PGAERR | WRPERR | OPERR
+const ERRORS: u32 = 0b1111_0010;
+
+#[derive(Copy, Clone)]
+pub struct Sector {
+    pub number: u8,
+    pub address: u32,
+    pub size: u32,
+}
+
+// memory.x stops the firmware at the end of sector 5, the last two 128K
+// sectors are left for the store.
+pub const STORE_SECTORS: [Sector; 2] = [
+    Sector {
+        number: 6,
+        address: 0x0804_0000,
+        size: 128 * 1024,
+    },
+    Sector {
+        number: 7,
+        address: 0x0806_0000,
+        size: 128 * 1024,
+    },
+];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    OutOfBounds,
+    Program,
+}
+
+/// Byte wide access to the internal flash. Erasing and programming stall
+/// the cpu as the firmware runs from the same bank.
+pub struct Flash {
+    flash: FLASH,
+}
+
+impl Flash {
+    pub fn new(flash: FLASH) -> Self {
+        Flash { flash }
+    }
+
+    pub fn read(&self, sector: &Sector, offset: u32, len: usize) -> &'static [u8] {
+        let len = core::cmp::min(len, sector.size.saturating_sub(offset) as usize);
+        unsafe { core::slice::from_raw_parts((sector.address + offset) as *const u8, len) }
+    }
+
+    pub fn erase(&mut self, sector: &Sector) -> Result<(), Error> {
+        self.unlock();
+        self.flash.cr.modify(|_, w| unsafe {
+            w.psize().bits(0b00).snb().bits(sector.number).ser().set_bit()
+        });
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait();
+        self.flash.cr.modify(|_, w| w.ser().clear_bit());
+        self.lock();
+        result
+    }
+
+    pub fn write(&mut self, sector: &Sector, offset: u32, data: &[u8]) -> Result<(), Error> {
+        if offset as usize + data.len() > sector.size as usize {
+            return Err(Error::OutOfBounds);
+        }
+
+        self.unlock();
+        self.flash
+            .cr
+            .modify(|_, w| unsafe { w.psize().bits(0b00).pg().set_bit() });
+
+        let mut result = Ok(());
+        for (i, byte) in data.iter().enumerate() {
+            let address = (sector.address + offset) as usize + i;
+            unsafe { core::ptr::write_volatile(address as *mut u8, *byte) };
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            self.flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+
+        let sr = self.flash.sr.read().bits();
+        if sr & ERRORS != 0 {
+            self.flash.sr.write(|w| unsafe { w.bits(sr & ERRORS) });
+            Err(Error::Program)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Storage for Flash {
+    fn size(&self) -> u32 {
+        STORE_SECTORS[0].size
+    }
+
+    fn read(&self, area: usize, offset: u32, len: usize) -> &[u8] {
+        Flash::read(self, &STORE_SECTORS[area], offset, len)
+    }
+
+    fn write(&mut self, area: usize, offset: u32, data: &[u8]) -> Result<(), store::Error> {
+        Flash::write(self, &STORE_SECTORS[area], offset, data).map_err(|_| store::Error::Storage)
+    }
+
+    fn erase(&mut self, area: usize) -> Result<(), store::Error> {
+        Flash::erase(self, &STORE_SECTORS[area]).map_err(|_| store::Error::Storage)
+    }
+}
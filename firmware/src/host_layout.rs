@@ -0,0 +1,83 @@
+use keyberon::key_code::{KeyCode, KeyCode::*};
+
+/// The keyboard layout the host has selected, needed to turn text back
+/// into key presses.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum HostLayout {
+    Us,
+    Uk,
+}
+
+impl HostLayout {
+    /// The key that types `c` on the host, and whether it needs shift.
+    pub fn key_for(self, c: char) -> Option<(KeyCode, bool)> {
+        let key = match self {
+            HostLayout::Us => None,
+            HostLayout::Uk => uk(c),
+        };
+        key.or_else(|| us(c))
+    }
+}
+
+fn uk(c: char) -> Option<(KeyCode, bool)> {
+    Some(match c {
+        '"' => (Kb2, true),
+        '@' => (Quote, true),
+        '£' => (Kb3, true),
+        '#' => (NonUsHash, false),
+        '~' => (NonUsHash, true),
+        '\\' => (NonUsBslash, false),
+        '|' => (NonUsBslash, true),
+        '¬' => (Grave, true),
+        _ => return None,
+    })
+}
+
+fn us(c: char) -> Option<(KeyCode, bool)> {
+    const LETTERS: [KeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [Kb0, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6, Kb7, Kb8, Kb9];
+
+    Some(match c {
+        'a'..='z' => (LETTERS[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LETTERS[c as usize - 'A' as usize], true),
+        '0'..='9' => (DIGITS[c as usize - '0' as usize], false),
+        '!' => (Kb1, true),
+        '@' => (Kb2, true),
+        '#' => (Kb3, true),
+        '$' => (Kb4, true),
+        '%' => (Kb5, true),
+        '^' => (Kb6, true),
+        '&' => (Kb7, true),
+        '*' => (Kb8, true),
+        '(' => (Kb9, true),
+        ')' => (Kb0, true),
+        ' ' => (Space, false),
+        '\n' => (Enter, false),
+        '\t' => (Tab, false),
+        '-' => (Minus, false),
+        '_' => (Minus, true),
+        '=' => (Equal, false),
+        '+' => (Equal, true),
+        '[' => (LBracket, false),
+        '{' => (LBracket, true),
+        ']' => (RBracket, false),
+        '}' => (RBracket, true),
+        '\\' => (Bslash, false),
+        '|' => (Bslash, true),
+        ';' => (SColon, false),
+        ':' => (SColon, true),
+        '\'' => (Quote, false),
+        '"' => (Quote, true),
+        '`' => (Grave, false),
+        '~' => (Grave, true),
+        ',' => (Comma, false),
+        '<' => (Comma, true),
+        '.' => (Dot, false),
+        '>' => (Dot, true),
+        '/' => (Slash, false),
+        '?' => (Slash, true),
+        _ => return None,
+    })
+}
@@ -0,0 +1,193 @@
+use keyberon::key_code::KeyCode;
+
+use crate::report::KbHidReport;
+
+/// While `mods` are held, `key` is replaced by `replacement` and `mods`
+/// are taken out of the report. Modifiers match either hand, so `LShift`
+/// also means `RShift`.
+pub struct KeyOverride {
+    pub mods: &'static [KeyCode],
+    pub key: KeyCode,
+    pub replacement: &'static [KeyCode],
+}
+
+/// Checks an override table at compile time: every override needs at
+/// least one modifier, only modifiers, and a key that isn't one.
+pub const fn is_valid(overrides: &[KeyOverride]) -> bool {
+    let mut i = 0;
+    while i < overrides.len() {
+        let o = &overrides[i];
+        if o.mods.is_empty() || is_modifier(o.key) {
+            return false;
+        }
+        let mut j = 0;
+        while j < o.mods.len() {
+            if !is_modifier(o.mods[j]) {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn is_modifier(kc: KeyCode) -> bool {
+    kc as u8 >= KeyCode::LCtrl as u8 && kc as u8 <= KeyCode::RGui as u8
+}
+
+// modifier bits with the right hand folded onto the left
+fn either_hand(bits: u8) -> u8 {
+    (bits | bits >> 4) & 0x0f
+}
+
+/// Applies an override table to the reports.
+pub struct Overrides {
+    overrides: &'static [KeyOverride],
+    swallow: Option<u8>,
+}
+
+impl Overrides {
+    pub fn new(overrides: &'static [KeyOverride]) -> Self {
+        Overrides {
+            overrides,
+            swallow: None,
+        }
+    }
+
+    /// Applies the first override that matches the report. Once a key has
+    /// been replaced, letting go of the modifiers first doesn't type it,
+    /// it is kept out of the report until it is released.
+    pub fn modify_report(&mut self, report: &mut KbHidReport) {
+        if let Some(code) = self.swallow {
+            if !report.keys().any(|k| k == code) {
+                self.swallow = None;
+            }
+        }
+
+        let held = either_hand(report.modifiers());
+        for o in self.overrides.iter() {
+            let mods = o
+                .mods
+                .iter()
+                .fold(0, |bits, kc| bits | either_hand(kc.as_modifier_bit()));
+            if held & mods == mods && report.keys().any(|k| k == o.key as u8) {
+                self.swallow = Some(o.key as u8);
+                report.released(o.key as u8);
+                report.release_modifiers(mods | mods << 4);
+                for kc in o.replacement.iter() {
+                    report.pressed(*kc);
+                }
+                return;
+            }
+        }
+
+        if let Some(code) = self.swallow {
+            report.released(code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyCode::*;
+
+    static OVERRIDES: &[KeyOverride] = &[
+        KeyOverride {
+            mods: &[LShift],
+            key: Comma,
+            replacement: &[SColon],
+        },
+        KeyOverride {
+            mods: &[LGui],
+            key: Escape,
+            replacement: &[LGui, Grave],
+        },
+    ];
+
+    // the report the override makes of the keys held
+    fn report(overrides: &mut Overrides, keys: &[KeyCode]) -> KbHidReport {
+        let mut report = keys.iter().copied().collect();
+        overrides.modify_report(&mut report);
+        report
+    }
+
+    fn expected(keys: &[KeyCode]) -> KbHidReport {
+        keys.iter().copied().collect()
+    }
+
+    #[test]
+    fn valid() {
+        assert!(is_valid(OVERRIDES));
+        assert!(!is_valid(&[KeyOverride {
+            mods: &[],
+            key: Comma,
+            replacement: &[SColon],
+        }]));
+        assert!(!is_valid(&[KeyOverride {
+            mods: &[A],
+            key: Comma,
+            replacement: &[SColon],
+        }]));
+    }
+
+    #[test]
+    fn either_hand_mods() {
+        let mut overrides = Overrides::new(OVERRIDES);
+        assert_eq!(
+            report(&mut overrides, &[RShift, Comma]),
+            expected(&[SColon])
+        );
+    }
+
+    #[test]
+    fn replacement_keeps_its_mods() {
+        let mut overrides = Overrides::new(OVERRIDES);
+        assert_eq!(
+            report(&mut overrides, &[LGui, Escape]),
+            expected(&[LGui, Grave])
+        );
+        assert_eq!(report(&mut overrides, &[LGui]), expected(&[LGui]));
+    }
+
+    #[test]
+    fn mods_released_first() {
+        let mut overrides = Overrides::new(OVERRIDES);
+        assert_eq!(report(&mut overrides, &[LShift]), expected(&[LShift]));
+        assert_eq!(
+            report(&mut overrides, &[LShift, Comma]),
+            expected(&[SColon])
+        );
+        // the comma isn't typed when shift goes first
+        assert_eq!(report(&mut overrides, &[Comma]), expected(&[]));
+        assert_eq!(report(&mut overrides, &[]), expected(&[]));
+        assert_eq!(report(&mut overrides, &[Comma]), expected(&[Comma]));
+    }
+
+    #[test]
+    fn key_released_first() {
+        let mut overrides = Overrides::new(OVERRIDES);
+        assert_eq!(
+            report(&mut overrides, &[LShift, Comma]),
+            expected(&[SColon])
+        );
+        // shift is back for the keys that follow
+        assert_eq!(report(&mut overrides, &[LShift]), expected(&[LShift]));
+        assert_eq!(report(&mut overrides, &[LShift, A]), expected(&[LShift, A]));
+        assert_eq!(
+            report(&mut overrides, &[LShift, Comma]),
+            expected(&[SColon])
+        );
+    }
+
+    #[test]
+    fn other_keys_pass() {
+        let mut overrides = Overrides::new(OVERRIDES);
+        assert_eq!(
+            report(&mut overrides, &[LCtrl, Comma]),
+            expected(&[LCtrl, Comma])
+        );
+        assert_eq!(report(&mut overrides, &[Comma, A]), expected(&[Comma, A]));
+    }
+}
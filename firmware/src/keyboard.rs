@@ -1,5 +1,14 @@
 use keyberon::hid::{HidDevice, Protocol, ReportType, Subclass};
-use keyberon::key_code::KeyCode;
+
+pub use crate::report::KbHidReport;
+
+use crate::dispatcher::Message;
+use crate::dynamic_keymap::KeyEdit;
+
+/// Output report for editing the keymap from the host, `[KEYMAP_REPORT_ID,
+/// layer, i, j, key code]`. Key code 0 puts back the compiled key, layer
+/// 0xff puts back every key.
+pub const KEYMAP_REPORT_ID: u8 = 3;
 
 #[rustfmt::skip]
 const REPORT_DESCRIPTOR : &[u8] = &[
@@ -47,6 +56,17 @@ const REPORT_DESCRIPTOR : &[u8] = &[
     0x2A, 0xFF, 0x07,               //      Usage Maximum (2047)
     0x81, 0x00,                     //      Input (Data, Ary, Abs)
     0xC0,
+    0x06, 0x60, 0xFF,  // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,        // Usage (0x61)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, 0x03,        //   Report ID (3)
+    0x09, 0x62,        //   Usage (0x62)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x04,        //   Report Count (4)
+    0x75, 0x08,        //   Report Size (8)
+    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+    0xC0,              // End Collection
 ];
 
 #[derive(Clone, Copy)]
@@ -68,6 +88,7 @@ pub enum MediaKey {
 pub struct Peautkb {
     report: MediaKeyHidReport,
     kb_report: KbHidReport,
+    keymap_request: Option<Message>,
 }
 
 impl Peautkb {
@@ -88,6 +109,11 @@ impl Peautkb {
             true
         }
     }
+
+    /// The keymap edit the host last sent, if it hasn't been taken yet.
+    pub fn take_keymap_request(&mut self) -> Option<Message> {
+        self.keymap_request.take()
+    }
 }
 
 impl HidDevice for Peautkb {
@@ -115,11 +141,27 @@ impl HidDevice for Peautkb {
 
     fn set_report(
         &mut self,
-        _report_type: ReportType,
-        _report_id: u8,
-        _data: &[u8],
+        report_type: ReportType,
+        report_id: u8,
+        data: &[u8],
     ) -> Result<(), ()> {
-        Ok(())
+        match (report_type, report_id, data) {
+            (ReportType::Output, KEYMAP_REPORT_ID, [_, 0xff, ..]) => {
+                self.keymap_request = Some(Message::ResetKeymap);
+                Ok(())
+            }
+            (ReportType::Output, KEYMAP_REPORT_ID, [_, layer, i, j, key_code]) => {
+                self.keymap_request = Some(Message::EditKey(KeyEdit {
+                    layer: *layer,
+                    i: *i,
+                    j: *j,
+                    key_code: *key_code,
+                }));
+                Ok(())
+            }
+            (ReportType::Output, KEYMAP_REPORT_ID, _) => Err(()),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -148,55 +190,3 @@ impl From<&MediaKey> for MediaKeyHidReport {
         rep
     }
 }
-
-#[derive(Clone, Eq, PartialEq)]
-pub struct KbHidReport([u8; 9]);
-
-impl core::iter::FromIterator<KeyCode> for KbHidReport {
-    fn from_iter<T>(iter: T) -> Self
-    where
-        T: IntoIterator<Item = KeyCode>,
-    {
-        let mut res = Self::default();
-        for kc in iter {
-            res.pressed(kc);
-        }
-        res
-    }
-}
-
-impl Default for KbHidReport {
-    fn default() -> Self {
-        let mut res = KbHidReport([0; 9]);
-        res.0[0] = 1;
-        res
-    }
-}
-
-impl KbHidReport {
-    /// Returns the byte slice corresponding to the report.
-    pub fn as_bytes(&mut self) -> &[u8] {
-        &self.0
-    }
-
-    /// Add the given key code to the report. If the report is full,
-    /// it will be set to `ErrorRollOver`.
-    pub fn pressed(&mut self, kc: KeyCode) {
-        use KeyCode::*;
-        match kc {
-            No => (),
-            ErrorRollOver | PostFail | ErrorUndefined => self.set_all(kc),
-            kc if kc.is_modifier() => self.0[1] |= kc.as_modifier_bit(),
-            _ => self.0[3..]
-                .iter_mut()
-                .find(|c| **c == 0)
-                .map(|c| *c = kc as u8)
-                .unwrap_or_else(|| self.set_all(ErrorRollOver)),
-        }
-    }
-    fn set_all(&mut self, kc: KeyCode) {
-        for c in &mut self.0[2..] {
-            *c = kc as u8;
-        }
-    }
-}
@@ -1,32 +1,59 @@
+use crate::combo::{Combo, ComboAction};
+use crate::debounce;
 use crate::custom_action::PkbAction;
+use crate::dispatcher::{leds, DisplayedState, Message};
+use crate::host_layout::HostLayout;
+use crate::key_override::{self, KeyOverride};
 use crate::keyboard::MediaKey;
+use crate::layer_profile::{LayerProfile, Tint};
+use crate::layer_rules::LayerRule;
+use crate::leader::{self, LeaderAction, Sequence};
+use crate::one_shot::{self, Key};
+use crate::quadrature::StepMode;
+use crate::rotary::{self, Direction};
+use crate::switcher::Switcher;
+use crate::tap_hold::{Tap, TapDance};
 use keyberon::action::{d, k, l, m, Action, Action::*};
-use keyberon::key_code::KeyCode::*;
+use keyberon::key_code::{KeyCode, KeyCode::*};
+use keyberon::layout::Layers;
 use serde::{Deserialize, Serialize};
 
+const LEADER: Action<PkbAction> = Custom(PkbAction::Leader);
+const REC_1: Action<PkbAction> = Custom(PkbAction::MacroRecord(0));
+const REC_2: Action<PkbAction> = Custom(PkbAction::MacroRecord(1));
+const PLAY_1: Action<PkbAction> = Custom(PkbAction::MacroPlay(0));
+const PLAY_2: Action<PkbAction> = Custom(PkbAction::MacroPlay(1));
+const CAPS_WORD: Action<PkbAction> = Custom(PkbAction::CapsWord);
+const ARROW: Action<PkbAction> = Custom(PkbAction::SendString("->"));
+const FAT_ARROW: Action<PkbAction> = Custom(PkbAction::SendString("=>"));
 const PLAY_PAUSE: Action<PkbAction> = Custom(PkbAction::MediaKey(MediaKey::PlayPause));
 const NEXT: Action<PkbAction> = Custom(PkbAction::MediaKey(MediaKey::NextTrack));
 const PREVIOUS: Action<PkbAction> = Custom(PkbAction::MediaKey(MediaKey::PrevTrack));
 
-const START_CMDT: Action<PkbAction> = MultipleActions(&[
-    Custom(PkbAction::HoldCmd),
-    d(Layer::Tabbing as usize),
-    k(Tab),
-]);
-const END_CMDT: Action<PkbAction> =
-    MultipleActions(&[Custom(PkbAction::ReleaseCmd), d(Layer::Default as usize)]);
+// tab through apps, or browser tabs, hold shift to go back
+const APP_SWITCHER: Switcher = Switcher {
+    mods: &[LGui],
+    cycle: &[Tab, Left, Right, Up, Down],
+    timeout: 1500,
+};
+const TAB_SWITCHER: Switcher = Switcher {
+    mods: &[LCtrl],
+    cycle: &[Tab, Left, Right],
+    timeout: 1500,
+};
+const CMD_TAB: Action<PkbAction> =
+    MultipleActions(&[k(Tab), Custom(PkbAction::Switcher(&APP_SWITCHER))]);
+const CTRL_TAB: Action<PkbAction> =
+    MultipleActions(&[k(Tab), Custom(PkbAction::Switcher(&TAB_SWITCHER))]);
 
-const START_CTRLT: Action<PkbAction> = MultipleActions(&[
-    Custom(PkbAction::HoldCtrl),
-    d(Layer::Tabbing as usize),
-    k(Tab),
-]);
-const END_CTRLT: Action<PkbAction> =
-    MultipleActions(&[Custom(PkbAction::ReleaseCtrl), d(Layer::Default as usize)]);
-const SHFT_TAB: Action<PkbAction> = m(&[LShift, Tab]);
+// what these do depends on the encoder mode, `ENC_MODE` cycles through them
+const ENC_CW: Action<PkbAction> = Custom(PkbAction::EncoderTurn(Direction::CW));
+const ENC_CCW: Action<PkbAction> = Custom(PkbAction::EncoderTurn(Direction::ACW));
+const ENC_MODE: Action<PkbAction> = Custom(PkbAction::CycleEncoderMode);
 
 const MENU_OPEN: Action<PkbAction> =
     MultipleActions(&[Custom(PkbAction::MenuOpen), d(Layer::Menu as usize)]);
+// The encoders move through the menu too.
 const MENU_UP: Action<PkbAction> = Custom(PkbAction::MenuUp);
 const MENU_DOWN: Action<PkbAction> = Custom(PkbAction::MenuDown);
 const MENU_LEFT: Action<PkbAction> = Custom(PkbAction::MenuLeft);
@@ -41,6 +68,57 @@ macro_rules! s {
     };
 }
 
+// tap for the key, hold for the modifier
+macro_rules! mt {
+    ($k:ident, $m:ident) => {
+        MultipleActions(&[k($m), Custom(PkbAction::TapHold($k))])
+    };
+}
+
+// tap for the key, hold for the layer
+macro_rules! lt {
+    ($l:expr, $k:ident) => {
+        MultipleActions(&[l($l as usize), Custom(PkbAction::TapHold($k))])
+    };
+}
+
+// tap for the next key only, tap twice to lock
+macro_rules! osm {
+    ($m:ident) => {
+        MultipleActions(&[k($m), Custom(PkbAction::OneShotMod($m))])
+    };
+}
+
+macro_rules! osl {
+    ($l:expr) => {
+        MultipleActions(&[l($l as usize), Custom(PkbAction::OneShotLayer($l))])
+    };
+}
+
+const OS_SHIFT: Action<PkbAction> = osm!(LShift);
+const OS_SYM: Action<PkbAction> = osl!(Layer::Symbols);
+
+const HM_A: Action<PkbAction> = mt!(A, LGui);
+const HM_R: Action<PkbAction> = mt!(R, LAlt);
+const HM_S: Action<PkbAction> = mt!(S, LCtrl);
+const HM_T: Action<PkbAction> = mt!(T, LShift);
+const HM_N: Action<PkbAction> = mt!(N, RShift);
+const HM_E: Action<PkbAction> = mt!(E, RCtrl);
+const HM_I: Action<PkbAction> = mt!(I, RAlt);
+const HM_O: Action<PkbAction> = mt!(O, RGui);
+
+const NUM_BS: Action<PkbAction> = lt!(Layer::Numbers, BSpace);
+const SYM_DEL: Action<PkbAction> = lt!(Layer::Symbols, Delete);
+
+const SC_CO: TapDance = TapDance {
+    taps: &[&[SColon], &[LShift, SColon]],
+    term: 175,
+};
+const SC_SYM: Action<PkbAction> = MultipleActions(&[
+    l(Layer::Symbols as usize),
+    Custom(PkbAction::TapDance(&SC_CO)),
+]);
+
 const LC: Action<PkbAction> = s!(LBracket);
 const RC: Action<PkbAction> = s!(RBracket);
 
@@ -59,51 +137,96 @@ const QU: Action<PkbAction> = k(Quote);
 const HASH: Action<PkbAction> = m(&[LAlt, Kb3]);
 const TILDA: Action<PkbAction> = s!(Grave);
 
+// `Layer`, `LAYERS` and `LAYER_ROW`, see keymap.toml. `LAYERS` is the
+// factory keymap, the layout runs on a copy with the edits from flash.
+include!(concat!(env!("OUT_DIR"), "/layers.rs"));
+
+// What the host turns key presses into, used to type out strings.
+pub const HOST_LAYOUT: HostLayout = HostLayout::Us;
+// Milliseconds each key of a typed string is held, and released, for.
+pub const TYPING_INTERVAL: u16 = 5;
+
+pub const ONE_SHOT: one_shot::Config = one_shot::Config {
+    mod_timeout: 1000,
+    layer_timeout: 1000,
+};
+
+// What each turn does is bound per layer in keymap.toml.
+pub const ENCODER: rotary::Config = rotary::Config {
+    step_mode: StepMode::FullStep,
+    sampling: rotary::Sampling::Interrupt,
+    acceleration: Some(rotary::Acceleration {
+        interval: 50,
+        max_steps: 4,
+    }),
+};
+
+// Until changed from the menu.
+pub const DEBOUNCE: debounce::Config = debounce::Config {
+    strategy: debounce::Strategy::Global,
+    time: 5,
+};
+
+// Milliseconds without typing before caps word turns itself off.
+pub const CAPS_WORD_TIMEOUT: u16 = 5000;
+
+// Milliseconds the leader key waits for each key of a sequence.
+pub const LEADER_TIMEOUT: u16 = 1000;
+
+// Typed after the leader key, before the timeout.
 #[rustfmt::skip]
-pub static LAYERS: keyberon::layout::Layers<PkbAction> = &[
-    &[
-        &[k(Tab),     k(Q),         k(W),     k(F),       k(P),          k(B),      k(Escape),          k(Insert),  k(J),     k(L),              k(U),        k(Y),      k(Quote),   k(SColon)],
-        &[k(LCtrl),   k(A),         k(R),     k(S),       k(T),          k(G),      MENU_OPEN,          k(Delete),  k(M),     k(N),              k(E),        k(I),      k(O),       k(Bslash)],
-        &[k(LShift),  k(Z),         k(X),     k(C),       k(D),          k(V),      k(Mute),            PLAY_PAUSE, k(K),     k(H),              k(Comma),    k(Dot),    k(Slash),   k(RShift)],
-        &[k(VolUp),   k(VolDown),   k(LAlt),  k(LGui),    l(1),          k(Enter),  k(LShift),          k(RShift),  k(Space), l(2),              k(RCtrl),    k(RAlt),   PREVIOUS,   NEXT],
-    ], 
-    &[
-        &[Trans,      k(F1),        k(F2),    k(F3),      k(F4),         k(F5),     k(F6),              k(F7),      k(F8),    k(F9),             k(F10),      k(F11),    k(F12),     Trans],
-        &[Trans,      k(Kb1),       k(Kb2),   k(Kb3),     k(Kb4),        k(Kb5),    NoOp,               NoOp,       k(Kb6),   k(Kb7),            k(Kb8),      k(Kb9),    k(Kb0),     Trans],
-        &[Trans,      k(Grave),     NoOp,     NoOp,       k(Minus),      k(Equal),  Trans,              Trans,      NoOp,     k(LBracket),       k(RBracket), NoOp,      NoOp,       Trans],
-        &[Trans,      Trans,        Trans,    Trans,      Trans,         Trans,     Trans,              Trans,      k(BSpace),Trans,             Trans,       Trans,     Trans,      Trans],
-    ],    
-    &[
-        &[Trans,      NoOp,         NoOp,     HASH,       DQ,            NoOp,      NoOp,               NoOp,       NoOp,     QU,                TILDA,       NoOp,      NoOp,       NoOp],
-        &[Trans,      NoOp,         LS,       LB,         LC,            CO,        NoOp,               NoOp,       SC,       RC,                RB,          RS,        NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     k(Minus),   s!(Minus),     NoOp,      NoOp,               NoOp,       NoOp,     k(Equal),          s!(Equal),   NoOp,      NoOp,       NoOp],
-        &[Trans,      Trans,        Trans,    Trans,      Trans,         Trans,     Trans,              Trans,      Trans,    Trans,             Trans,       Trans,     Trans,      Trans],
-    ],
-    &[   
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      NoOp,               NoOp,       NoOp,     NoOp,              k(Up),       NoOp,      NoOp,       NoOp],
-        &[Trans,      k(Home),      k(PgUp),  k(PgDown),  k(End),        NoOp,      NoOp,               NoOp,       NoOp,     k(Left),           k(Down),     k(Right),  NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      START_CTRLT,        START_CMDT, NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      Trans,        Trans,    Trans,      Trans,         Trans,     Trans,              Trans,      Trans,    Trans,             Trans,       Trans,     Trans,      Trans],
-    ],     
-    &[   
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      NoOp,               NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      NoOp,               NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      END_CTRLT,          END_CMDT,   NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[k(Tab),     SHFT_TAB,     Trans,    Trans,      Trans,         Trans,     Trans,              Trans,      Trans,    Trans,             Trans,       Trans,     k(Left),    k(Right)],
-    ],    
-    &[   
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      NoOp,               NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      MENU_CLOSE,         NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      NoOp,         NoOp,     NoOp,       NoOp,          NoOp,      MENU_SELECT,        NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[MENU_DOWN,  MENU_UP,      Trans,    Trans,      Trans,         Trans,     Trans,              Trans,      Trans,    Trans,             Trans,       Trans,     MENU_LEFT,  MENU_RIGHT],
-    ],   
-    // CS    
-    &[   
-        &[k(Tab),     k(F),         k(Kb3),   k(W),       k(E),          k(R),      k(Escape),          NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      k(LShift),    k(A),     k(S),       k(D),          k(G),      MENU_OPEN,          NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[Trans,      k(LCtrl),     k(X),     k(T),       k(Kb5),        k(B),      k(Mute),            NoOp,       NoOp,     NoOp,              NoOp,        NoOp,      NoOp,       NoOp],
-        &[k(VolUp),   k(VolDown),   k(Kb1),   k(Kb2),     k(Space),      k(Kb6),    k(Kb7),             Trans,      Trans,    Trans,             Trans,       Trans,     NoOp,       NoOp],
-    ], 
+pub const LEADER_SEQUENCES: &[Sequence] = &[
+    Sequence { keys: &[L, O],    action: LeaderAction::Message(Message::LED(leds::Action::SetMode(leds::Mode::Off))) },
+    Sequence { keys: &[L, S],    action: LeaderAction::Message(Message::LED(leds::Action::SetMode(leds::Mode::Solid))) },
+    Sequence { keys: &[L, W],    action: LeaderAction::Message(Message::LED(leds::Action::SetMode(leds::Mode::Wheel))) },
+    Sequence { keys: &[L, F],    action: LeaderAction::Message(Message::LED(leds::Action::SetMode(leds::Mode::Fade))) },
+    Sequence { keys: &[G],       action: LeaderAction::Layer(Layer::CS) },
+    Sequence { keys: &[D],       action: LeaderAction::Layer(Layer::Default) },
+    Sequence { keys: &[S, S],    action: LeaderAction::Keys(&[&[LGui, LShift, Kb4]]) },
+    Sequence { keys: &[E, U],    action: LeaderAction::Unicode('€') },
+    Sequence { keys: &[P, I],    action: LeaderAction::Unicode('π') },
+    Sequence { keys: &[A, R],    action: LeaderAction::Unicode('→') },
+];
+
+// fails to build if a sequence is empty, too long or shadowed by another
+const _: [(); 0 - !leader::is_valid(LEADER_SEQUENCES) as usize] = [];
+
+// Holding both layers gives the third one instead.
+#[rustfmt::skip]
+pub static LAYER_RULES: &[LayerRule] = &[
+    LayerRule { layers: [Layer::Numbers, Layer::Symbols], result: Layer::Navigation },
+];
+
+// Switched to while the layer is current, on both halves.
+#[rustfmt::skip]
+pub static LAYER_PROFILES: &[LayerProfile] = &[
+    LayerProfile { layer: Layer::CS,         leds: Some(leds::Mode::Fade), display: Some(DisplayedState::Stats),  tint: None },
+    LayerProfile { layer: Layer::Numbers,    leds: None,                   display: Some(DisplayedState::Legend), tint: None },
+    LayerProfile { layer: Layer::Symbols,    leds: None,                   display: Some(DisplayedState::Legend), tint: None },
+    LayerProfile { layer: Layer::Navigation, leds: None,                   display: Some(DisplayedState::Legend), tint: Some(ARROWS) },
+];
+
+const ARROWS: Tint = Tint {
+    keys: &[(0, 10), (1, 9), (1, 10), (1, 11)],
+    colour: (0, 0x60, 0xff),
+};
+
+// Applied to the report, the first match wins.
+#[rustfmt::skip]
+pub static KEY_OVERRIDES: &[KeyOverride] = &[
+    KeyOverride { mods: &[LShift], key: BSpace, replacement: &[Delete] },
+    KeyOverride { mods: &[LGui],   key: Escape, replacement: &[LGui, Grave] },
+];
+
+// fails to build if an override has no modifiers, or a modifier as its key
+const _: [(); 0 - !key_override::is_valid(KEY_OVERRIDES) as usize] = [];
+
+// Right half coordinates are the mirrored ones the layout uses, (3, 9) is
+// the right inner thumb key.
+#[rustfmt::skip]
+pub static COMBOS: &[Combo<Layer>] = &[
+    Combo { keys: &[(1, 3), (1, 4)], action: ComboAction::Key(Escape),                     layers: &[Layer::Default], timeout: 40 },
+    Combo { keys: &[(3, 4), (3, 9)], action: ComboAction::ToggleLayer(Layer::Navigation), layers: &[],               timeout: 60 },
 ];
 
 // &[
@@ -113,65 +236,104 @@ pub static LAYERS: keyberon::layout::Layers<PkbAction> = &[
 //         &[Trans,      Trans,        Trans,    Trans,      Trans,      Trans,     Trans,              Trans,      Trans,    Trans,       Trans,       Trans,     Trans,    Trans],
 //     ],
 
-#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub enum Layer {
-    Default,
-    Numbers,
-    Symbols,
-    Navigation,
-    Tabbing,
-    Menu,
-    CS,
-    Missing,
+fn custom_tap(action: &'static Action<PkbAction>) -> Option<Tap> {
+    match action {
+        Custom(PkbAction::TapHold(kc)) => Some(Tap::Key(kc)),
+        Custom(PkbAction::TapDance(dance)) => Some(Tap::Dance(*dance)),
+        _ => None,
+    }
 }
 
-impl Default for Layer {
-    fn default() -> Self {
-        Layer::Default
+fn action_at(
+    layers: Layers<PkbAction>,
+    layer: usize,
+    default_layer: usize,
+    i: u8,
+    j: u8,
+) -> &'static Action<PkbAction> {
+    let action = layers
+        .get(layer)
+        .and_then(|l| l.get(i as usize))
+        .and_then(|r| r.get(j as usize));
+
+    match action {
+        Some(Trans) if layer != default_layer => {
+            action_at(layers, default_layer, default_layer, i, j)
+        }
+        Some(action) => action,
+        None => &NoOp,
     }
 }
 
-impl From<usize> for Layer {
-    fn from(i: usize) -> Layer {
-        match i {
-            0 => Layer::Default,
-            1 => Layer::Numbers,
-            2 => Layer::Symbols,
-            3 => Layer::Navigation,
-            4 => Layer::Tabbing,
-            5 => Layer::Menu,
-            6 => Layer::CS,
-            _ => Layer::Missing,
-        }
+/// What the tap-hold or tap dance key at `(i, j)` sends when tapped, if it is one.
+pub fn tap_for(
+    layers: Layers<PkbAction>,
+    layer: usize,
+    default_layer: usize,
+    i: u8,
+    j: u8,
+) -> Option<Tap> {
+    match action_at(layers, layer, default_layer, i, j) {
+        MultipleActions(actions) => actions.iter().find_map(custom_tap),
+        action => custom_tap(action),
     }
 }
 
-impl From<Layer> for usize {
-    fn from(layer: Layer) -> Self {
-        match layer {
-            Layer::Default => 0,
-            Layer::Numbers => 1,
-            Layer::Symbols => 2,
-            Layer::Navigation => 3,
-            Layer::Tabbing => 4,
-            Layer::Menu => 5,
-            Layer::CS => 6,
-            Layer::Missing => 8,
-        }
+fn custom_one_shot(action: &'static Action<PkbAction>) -> Option<Key<Layer>> {
+    match action {
+        Custom(PkbAction::OneShotMod(kc)) => Some(Key::Mod(*kc)),
+        Custom(PkbAction::OneShotLayer(layer)) => Some(Key::Layer(*layer)),
+        _ => None,
     }
 }
 
-impl From<Layer> for &str {
-    fn from(layer: Layer) -> &'static str {
-        match layer {
-            Layer::Default => "default",
-            Layer::Numbers => "numbers",
-            Layer::Navigation => "nav",
-            Layer::Symbols => "symbols",
-            Layer::Tabbing => "tabbing",
-            Layer::Menu => "menu",
-            Layer::CS => "CS",
-            Layer::Missing => "missing",
-        }
+/// The one-shot modifier or layer at `(i, j)`, if it is one.
+pub fn one_shot_for(
+    layers: Layers<PkbAction>,
+    layer: usize,
+    default_layer: usize,
+    i: u8,
+    j: u8,
+) -> Option<Key<Layer>> {
+    match action_at(layers, layer, default_layer, i, j) {
+        MultipleActions(actions) => actions.iter().find_map(custom_one_shot),
+        action => custom_one_shot(action),
     }
 }
+
+/// The layer the key at `(i, j)` holds, if it is a layer key.
+pub fn layer_for(
+    layers: Layers<PkbAction>,
+    layer: usize,
+    default_layer: usize,
+    i: u8,
+    j: u8,
+) -> Option<Layer> {
+    let layer_of = |action: &Action<PkbAction>| match action {
+        Action::Layer(l) => Some(Layer::from(*l)),
+        _ => None,
+    };
+    match action_at(layers, layer, default_layer, i, j) {
+        MultipleActions(actions) => actions.iter().find_map(layer_of),
+        action => layer_of(action),
+    }
+}
+
+/// The key code a press at `(i, j)` would type, used to match leader sequences.
+pub fn key_code_for(
+    layers: Layers<PkbAction>,
+    layer: usize,
+    default_layer: usize,
+    i: u8,
+    j: u8,
+) -> Option<KeyCode> {
+    match (
+        action_at(layers, layer, default_layer, i, j),
+        tap_for(layers, layer, default_layer, i, j),
+    ) {
+        (_, Some(Tap::Key(kc))) => Some(*kc),
+        (Action::KeyCode(kc), _) => Some(*kc),
+        _ => None,
+    }
+}
+
@@ -0,0 +1,21 @@
+use crate::dispatcher::{leds, DisplayedState};
+use crate::keymap::Layer;
+
+/// While `layer` is the current layer each half switches to these, and
+/// goes back to its own once it isn't. `None` keeps what the half has.
+pub struct LayerProfile {
+    pub layer: Layer,
+    pub leds: Option<leds::Mode>,
+    pub display: Option<DisplayedState>,
+    pub tint: Option<Tint>,
+}
+
+/// Lights `keys`, in layout coordinates, in `colour` over the LED mode.
+pub struct Tint {
+    pub keys: &'static [(u8, u8)],
+    pub colour: (u8, u8, u8),
+}
+
+pub fn for_layer(profiles: &'static [LayerProfile], layer: Layer) -> Option<&'static LayerProfile> {
+    profiles.iter().find(|p| p.layer == layer)
+}
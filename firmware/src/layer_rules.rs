@@ -0,0 +1,91 @@
+use heapless::{
+    consts::{U4, U8},
+    Vec,
+};
+use keyberon::layout::Event;
+
+use crate::keymap::{Layer, LAYER_ROW};
+
+/// What to feed the layout, in order, in place of an event.
+pub type Events = Vec<Event, U4>;
+
+/// While both `layers` are held, `result` is active instead.
+pub struct LayerRule {
+    pub layers: [Layer; 2],
+    pub result: Layer,
+}
+
+/// Tracks the layer keys held in the layout. When a rule matches, those
+/// keys are let go of in the layout and the result layer is held from
+/// `LAYER_ROW`, a row of layer keys no switch is wired to. Letting go of
+/// either key goes back to the other one's layer.
+pub struct LayerRules {
+    rules: &'static [LayerRule],
+    held: Vec<((u8, u8), Layer), U8>,
+    active: Option<&'static LayerRule>,
+}
+
+impl LayerRules {
+    pub fn new(rules: &'static [LayerRule]) -> Self {
+        LayerRules {
+            rules,
+            held: Vec::new(),
+            active: None,
+        }
+    }
+
+    /// `layer` is the layer the key at a press holds, if any.
+    pub fn event(&mut self, event: Event, layer: Option<Layer>) -> Events {
+        let mut events = Events::new();
+        match (event, layer) {
+            (Event::Press(i, j), Some(layer)) => {
+                events.push(event).ok();
+                if self.held.push(((i, j), layer)).is_err() || self.active.is_some() {
+                    return events;
+                }
+
+                let held = &self.held;
+                let is_held = |l: Layer| held.iter().any(|(_, h)| *h == l);
+                if let Some(rule) = self.rules.iter().find(|r| r.layers.iter().all(|l| is_held(*l))) {
+                    for ((i, j), _) in self.participants(rule) {
+                        events.push(Event::Release(i, j)).ok();
+                    }
+                    events.push(Event::Press(LAYER_ROW, usize::from(rule.result) as u8)).ok();
+                    self.active = Some(rule);
+                }
+            }
+            (Event::Release(i, j), _) => match self.held.iter().position(|(c, _)| *c == (i, j)) {
+                Some(k) => {
+                    let (_, layer) = self.held.swap_remove(k);
+                    match self.active {
+                        Some(rule) if rule.layers.contains(&layer) => {
+                            self.active = None;
+                            events.push(Event::Release(LAYER_ROW, usize::from(rule.result) as u8)).ok();
+                            for ((i, j), _) in self.participants(rule) {
+                                events.push(Event::Press(i, j)).ok();
+                            }
+                        }
+                        _ => {
+                            events.push(event).ok();
+                        }
+                    }
+                }
+                None => {
+                    events.push(event).ok();
+                }
+            },
+            (Event::Press(_, _), None) => {
+                events.push(event).ok();
+            }
+        }
+        events
+    }
+
+    fn participants(&self, rule: &LayerRule) -> impl Iterator<Item = ((u8, u8), Layer)> + '_ {
+        let layers = rule.layers;
+        self.held
+            .iter()
+            .copied()
+            .filter(move |(_, l)| layers.contains(l))
+    }
+}
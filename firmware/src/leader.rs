@@ -0,0 +1,142 @@
+use heapless::{
+    consts::{U4, U8},
+    Vec,
+};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+
+use crate::dispatcher::Message;
+use crate::keymap::Layer;
+
+pub const MAX_LENGTH: usize = 4;
+
+#[derive(Copy, Clone)]
+pub enum LeaderAction {
+    Keys(&'static [&'static [KeyCode]]),
+    Message(Message),
+    Layer(Layer),
+    Unicode(char),
+}
+
+pub struct Sequence {
+    pub keys: &'static [KeyCode],
+    pub action: LeaderAction,
+}
+
+/// Checks a sequence table at compile time: every sequence has between 1
+/// and `MAX_LENGTH` keys and none of them is the start of another one, as
+/// that one could never be reached.
+pub const fn is_valid(sequences: &[Sequence]) -> bool {
+    let mut i = 0;
+    while i < sequences.len() {
+        let keys = sequences[i].keys;
+        if keys.is_empty() || keys.len() > MAX_LENGTH {
+            return false;
+        }
+        let mut j = 0;
+        while j < sequences.len() {
+            if i != j && starts_with(sequences[j].keys, keys) {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn starts_with(keys: &[KeyCode], prefix: &[KeyCode]) -> bool {
+    if prefix.len() > keys.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < prefix.len() {
+        if keys[i] as u8 != prefix[i] as u8 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+pub enum Step {
+    Pass,
+    Swallow,
+    Captured(KeyCode),
+    Matched(&'static Sequence),
+    Cancelled,
+}
+
+pub struct Leader {
+    sequences: &'static [Sequence],
+    timeout: u16,
+    active: bool,
+    since: u32,
+    keys: Vec<KeyCode, U4>,
+    captured: Vec<(u8, u8), U8>,
+}
+
+impl Leader {
+    pub fn new(sequences: &'static [Sequence], timeout: u16) -> Self {
+        Leader {
+            sequences,
+            timeout,
+            active: false,
+            since: 0,
+            keys: Vec::new(),
+            captured: Vec::new(),
+        }
+    }
+
+    pub fn start(&mut self, now: u32) {
+        self.active = true;
+        self.since = now;
+        self.keys.clear();
+    }
+
+    /// `key_code` is what the layout would send for a press, if anything.
+    pub fn event(&mut self, now: u32, event: Event, key_code: Option<KeyCode>) -> Step {
+        match event {
+            Event::Release(i, j) => match self.captured.iter().position(|c| *c == (i, j)) {
+                Some(k) => {
+                    self.captured.swap_remove(k);
+                    Step::Swallow
+                }
+                None => Step::Pass,
+            },
+            Event::Press(_, _) if !self.active => Step::Pass,
+            Event::Press(i, j) => {
+                self.captured.push((i, j)).ok();
+                let kc = match key_code {
+                    Some(kc) if self.keys.push(kc).is_ok() => kc,
+                    _ => return self.cancel(),
+                };
+
+                let keys = &self.keys;
+                let sequences = self.sequences;
+                if let Some(sequence) = sequences.iter().find(|s| s.keys == &keys[..]) {
+                    self.active = false;
+                    Step::Matched(sequence)
+                } else if sequences.iter().any(|s| s.keys.starts_with(keys)) {
+                    self.since = now;
+                    Step::Captured(kc)
+                } else {
+                    self.cancel()
+                }
+            }
+        }
+    }
+
+    pub fn tick(&mut self, now: u32) -> Option<Step> {
+        if self.active && now.wrapping_sub(self.since) >= self.timeout as u32 {
+            Some(self.cancel())
+        } else {
+            None
+        }
+    }
+
+    fn cancel(&mut self) -> Step {
+        self.active = false;
+        Step::Cancelled
+    }
+}
@@ -0,0 +1,218 @@
+use keyberon::action::Action;
+use keyberon::key_code::{KeyCode, KeyCode::*};
+
+use crate::custom_action::PkbAction;
+use crate::keyboard::MediaKey;
+use crate::keymap::Layer;
+use crate::rotary::Direction;
+
+const MAX: usize = 3;
+
+/// What a key shows on the legend screen, up to three characters. Layers
+/// are in lower case, keys and actions start with a capital.
+#[derive(Copy, Clone, Default)]
+pub struct Legend {
+    chars: [u8; MAX],
+    len: usize,
+}
+
+impl Legend {
+    fn new(s: &str) -> Self {
+        let mut legend = Legend::default();
+        legend.push_str(s);
+        legend
+    }
+
+    // Anything past three characters is cut off.
+    fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            if self.len < MAX {
+                self.chars[self.len] = b;
+                self.len += 1;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.chars[..self.len]).unwrap_or("")
+    }
+}
+
+/// Blank for `NoOp`, `_` for `Trans`. Tap-hold keys show their tap.
+pub fn of(action: &Action<PkbAction>) -> Legend {
+    match action {
+        Action::NoOp => Legend::default(),
+        Action::Trans => Legend::new("_"),
+        Action::KeyCode(k) => Legend::new(key_code(*k)),
+        Action::MultipleKeyCodes(keys) => chord(keys),
+        // the custom action says what the key is for, e.g. the tap of `mt!`
+        Action::MultipleActions(actions) => actions
+            .iter()
+            .filter_map(|a| match a {
+                Action::Custom(c) => Some(custom(c)),
+                _ => None,
+            })
+            .chain(actions.iter().map(of))
+            .find(|l| !l.is_empty())
+            .unwrap_or_default(),
+        Action::Layer(l) | Action::DefaultLayer(l) => layer(Layer::from(*l)),
+        Action::Custom(c) => custom(c),
+        // keyberon's own hold-tap isn't used, see `PkbAction::TapHold`
+        _ => Legend::new("?"),
+    }
+}
+
+fn layer(layer: Layer) -> Legend {
+    Legend::new(layer.into())
+}
+
+fn custom(action: &PkbAction) -> Legend {
+    match action {
+        PkbAction::MediaKey(key) => Legend::new(media_key(*key)),
+        PkbAction::MenuOpen => Legend::new("Mnu"),
+        PkbAction::MenuClose => Legend::new("Cls"),
+        PkbAction::MenuUp => Legend::new("Up"),
+        PkbAction::MenuDown => Legend::new("Dn"),
+        PkbAction::MenuSelect => Legend::new("Sel"),
+        PkbAction::MenuLeft => Legend::new("Lt"),
+        PkbAction::MenuRight => Legend::new("Rt"),
+        PkbAction::TapHold(k) => Legend::new(key_code(*k)),
+        PkbAction::TapDance(dance) => dance.taps.first().map(|t| chord(t)).unwrap_or_default(),
+        PkbAction::Leader => Legend::new("Ldr"),
+        PkbAction::MacroRecord(slot) => numbered(b'R', *slot),
+        PkbAction::MacroPlay(slot) => numbered(b'P', *slot),
+        PkbAction::SendString(s) => Legend::new(s),
+        PkbAction::OneShotMod(k) => Legend::new(key_code(*k)),
+        PkbAction::OneShotLayer(l) => layer(*l),
+        PkbAction::CapsWord => Legend::new("CW"),
+        PkbAction::Switcher(switcher) => {
+            let mut legend = mods(switcher.mods);
+            legend.push_str("Sw");
+            legend
+        }
+        PkbAction::EncoderTurn(Direction::CW) => Legend::new("E+"),
+        PkbAction::EncoderTurn(Direction::ACW) => Legend::new("E-"),
+        PkbAction::CycleEncoderMode => Legend::new("Enc"),
+    }
+}
+
+// Macro slots count from one, as on the info screen.
+fn numbered(prefix: u8, slot: u8) -> Legend {
+    let mut legend = Legend::default();
+    legend.chars[0] = prefix;
+    legend.chars[1] = b'1' + slot % 9;
+    legend.len = 2;
+    legend
+}
+
+// The shifted character with only shift held, otherwise a letter for each
+// modifier before the key.
+fn chord(keys: &[KeyCode]) -> Legend {
+    let key = keys.iter().rev().find(|k| !is_mod(**k));
+    let modifiers: &[KeyCode] = match key {
+        Some(_) => &keys[..keys.len() - 1],
+        None => keys,
+    };
+    let shift_only =
+        !modifiers.is_empty() && modifiers.iter().all(|m| matches!(m, LShift | RShift));
+
+    match key {
+        Some(k) if shift_only => Legend::new(shifted(*k).unwrap_or_else(|| key_code(*k))),
+        Some(k) => {
+            let mut legend = mods(modifiers);
+            legend.push_str(key_code(*k));
+            legend
+        }
+        None => mods(modifiers),
+    }
+}
+
+fn mods(keys: &[KeyCode]) -> Legend {
+    let mut legend = Legend::default();
+    for k in keys {
+        legend.push_str(match k {
+            LCtrl | RCtrl => "C",
+            LShift | RShift => "S",
+            LAlt | RAlt => "A",
+            LGui | RGui => "G",
+            _ => "",
+        });
+    }
+    legend
+}
+
+fn is_mod(k: KeyCode) -> bool {
+    (LCtrl as u8..=RGui as u8).contains(&(k as u8))
+}
+
+fn media_key(key: MediaKey) -> &'static str {
+    match key {
+        MediaKey::Record => "Rec",
+        MediaKey::FastForward => "FF",
+        MediaKey::Rewind => "Rew",
+        MediaKey::NextTrack => "Nxt",
+        MediaKey::PrevTrack => "Prv",
+        MediaKey::Stop => "Stp",
+        MediaKey::Eject | MediaKey::StopEject => "Ejt",
+        MediaKey::RandomPlay => "Rnd",
+        MediaKey::PlayPause => "Ply",
+    }
+}
+
+// On a US host, see `HostLayout`.
+fn shifted(k: KeyCode) -> Option<&'static str> {
+    Some(match k {
+        Kb1 => "!",
+        Kb2 => "@",
+        Kb3 => "#",
+        Kb4 => "$",
+        Kb5 => "%",
+        Kb6 => "^",
+        Kb7 => "&",
+        Kb8 => "*",
+        Kb9 => "(",
+        Kb0 => ")",
+        Minus => "_",
+        Equal => "+",
+        LBracket => "{",
+        RBracket => "}",
+        Bslash => "|",
+        SColon => ":",
+        Quote => "\"",
+        Grave => "~",
+        Comma => "<",
+        Dot => ">",
+        Slash => "?",
+        _ => return None,
+    })
+}
+
+#[rustfmt::skip]
+fn key_code(k: KeyCode) -> &'static str {
+    match k {
+        A => "A", B => "B", C => "C", D => "D", E => "E", F => "F", G => "G",
+        H => "H", I => "I", J => "J", K => "K", L => "L", M => "M", N => "N",
+        O => "O", P => "P", Q => "Q", R => "R", S => "S", T => "T", U => "U",
+        V => "V", W => "W", X => "X", Y => "Y", Z => "Z",
+        Kb1 => "1", Kb2 => "2", Kb3 => "3", Kb4 => "4", Kb5 => "5",
+        Kb6 => "6", Kb7 => "7", Kb8 => "8", Kb9 => "9", Kb0 => "0",
+        Enter => "Ent", Escape => "Esc", BSpace => "Bsp", Tab => "Tab", Space => "Spc",
+        Minus => "-", Equal => "=", LBracket => "[", RBracket => "]", Bslash => "\\",
+        NonUsHash => "#", SColon => ";", Quote => "'", Grave => "`", Comma => ",",
+        Dot => ".", Slash => "/", NonUsBslash => "\\", CapsLock => "Cap",
+        F1 => "F1", F2 => "F2", F3 => "F3", F4 => "F4", F5 => "F5", F6 => "F6",
+        F7 => "F7", F8 => "F8", F9 => "F9", F10 => "F10", F11 => "F11", F12 => "F12",
+        F13 => "F13", F14 => "F14", F15 => "F15", F16 => "F16", F17 => "F17", F18 => "F18",
+        F19 => "F19", F20 => "F20", F21 => "F21", F22 => "F22", F23 => "F23", F24 => "F24",
+        PScreen => "Prt", ScrollLock => "Scl", Pause => "Pau", Insert => "Ins",
+        Home => "Hom", PgUp => "PgU", Delete => "Del", End => "End", PgDown => "PgD",
+        Right => "Rt", Left => "Lt", Down => "Dn", Up => "Up",
+        Application => "App", Mute => "Mut", VolUp => "V+", VolDown => "V-",
+        LCtrl | RCtrl => "Ctl", LShift | RShift => "Sft", LAlt | RAlt => "Alt", LGui | RGui => "Gui",
+        _ => "?",
+    }
+}
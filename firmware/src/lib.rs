@@ -0,0 +1,20 @@
+//! The parts of the firmware that don't touch the hardware, so they build
+//! and are tested on the host as well:
+//! `cargo test --lib --target x86_64-unknown-linux-gnu`, or whichever
+//! target the host is. main.rs uses them from here.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod combo;
+pub mod debounce;
+pub mod diagnostics;
+pub mod key_override;
+pub mod macros;
+pub mod multi;
+pub mod one_shot;
+pub mod quadrature;
+pub mod report;
+pub mod settings;
+pub mod store;
+pub mod tap_hold;
+pub mod wpm;
@@ -0,0 +1,324 @@
+use heapless::{
+    consts::{U16, U64},
+    Vec,
+};
+
+use crate::report::KbHidReport;
+use crate::store::{self, key, Storage, Store};
+
+pub const SLOTS: usize = 2;
+
+// Each slot is kept in the store as its 4 byte steps.
+const VERSION: u8 = 1;
+const STEP: usize = 4;
+
+/// A key code the host got pressed or released, `delay` milliseconds
+/// after the step before it, or after the recording started.
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+struct Step {
+    code: u8,
+    press: bool,
+    delay: u16,
+}
+
+impl Step {
+    fn to_bytes(self) -> [u8; 4] {
+        let press = if self.press { 0x80 } else { 0 };
+        let delay = self.delay.to_le_bytes();
+        [press, self.code, delay[0], delay[1]]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Step {
+            code: bytes[1],
+            press: bytes[0] & 0x80 != 0,
+            delay: u16::from_le_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
+
+type Recording = Vec<Step, U64>;
+
+struct Playback {
+    slot: usize,
+    index: usize,
+    wait: u16,
+}
+
+/// Records the key codes of the reports sent to the host, so a macro
+/// plays back what was typed whatever layer, combo or tap-hold key made
+/// it, and the keys that start playback can't change it.
+#[derive(Default)]
+pub struct Macros {
+    slots: [Recording; SLOTS],
+    recording: Option<usize>,
+    last_change: u32,
+    // the key codes held in the recording, or by the playback
+    held: Vec<u8, U16>,
+    playback: Option<Playback>,
+    unsaved: Option<usize>,
+}
+
+impl Macros {
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn start_recording(&mut self, slot: usize, now: u32) -> bool {
+        if slot >= SLOTS || self.playback.is_some() {
+            return false;
+        }
+        self.slots[slot].clear();
+        self.held.clear();
+        self.recording = Some(slot);
+        self.last_change = now;
+        true
+    }
+
+    /// Every key code still held gets released.
+    pub fn stop_recording(&mut self) {
+        if let Some(slot) = self.recording.take() {
+            let recording = &mut self.slots[slot];
+            for code in self.held.iter() {
+                recording
+                    .push(Step {
+                        code: *code,
+                        press: false,
+                        delay: 0,
+                    })
+                    .ok();
+            }
+            self.held.clear();
+            self.unsaved = Some(slot);
+        }
+    }
+
+    /// Called with every report sent to the host, records the key codes
+    /// that changed since the last one.
+    pub fn record(&mut self, now: u32, report: &KbHidReport) {
+        let slot = match self.recording {
+            Some(slot) => slot,
+            None => return,
+        };
+        let (recording, held) = (&mut self.slots[slot], &mut self.held);
+        let mut delay = core::cmp::min(now.wrapping_sub(self.last_change), u16::MAX as u32) as u16;
+        let mut changed = false;
+
+        let mut k = 0;
+        while k < held.len() {
+            let code = held[k];
+            if report.codes().any(|c| c == code) {
+                k += 1;
+                continue;
+            }
+            held.swap_remove(k);
+            recording
+                .push(Step {
+                    code,
+                    press: false,
+                    delay,
+                })
+                .ok();
+            delay = 0;
+            changed = true;
+        }
+
+        for code in report.codes() {
+            // keep room to release everything that is held
+            if held.contains(&code)
+                || recording.len() + held.len() + 2 > recording.capacity()
+                || held.push(code).is_err()
+            {
+                continue;
+            }
+            recording
+                .push(Step {
+                    code,
+                    press: true,
+                    delay,
+                })
+                .ok();
+            delay = 0;
+            changed = true;
+        }
+
+        if changed {
+            self.last_change = now;
+        }
+    }
+
+    pub fn play(&mut self, slot: usize) {
+        if self.recording.is_none() && slot < SLOTS && !self.slots[slot].is_empty() {
+            self.held.clear();
+            self.playback = Some(Playback {
+                slot,
+                index: 0,
+                wait: self.slots[slot][0].delay,
+            });
+        }
+    }
+
+    /// Called once per tick, presses and releases the recorded key codes
+    /// that are due.
+    pub fn tick(&mut self) {
+        let playback = match self.playback.as_mut() {
+            Some(playback) => playback,
+            None => return,
+        };
+        let steps = &self.slots[playback.slot];
+        while playback.wait == 0 {
+            let step = match steps.get(playback.index) {
+                Some(step) => step,
+                None => {
+                    self.held.clear();
+                    self.playback = None;
+                    return;
+                }
+            };
+            if step.press {
+                self.held.push(step.code).ok();
+            } else if let Some(k) = self.held.iter().position(|c| *c == step.code) {
+                self.held.swap_remove(k);
+            }
+            playback.index += 1;
+            playback.wait = steps.get(playback.index).map_or(0, |s| s.delay);
+        }
+        playback.wait -= 1;
+    }
+
+    /// The key codes the playback holds, to add to the report.
+    pub fn keys(&self) -> &[u8] {
+        match self.playback {
+            Some(_) => &self.held,
+            None => &[],
+        }
+    }
+
+    pub fn load<S: Storage>(&mut self, store: &Store<S>) {
+        for (slot, recording) in self.slots.iter_mut().enumerate() {
+            if let Some((VERSION, steps)) = store.get(key::MACROS + slot as u8) {
+                for bytes in steps.chunks_exact(STEP) {
+                    recording.push(Step::from_bytes(bytes)).ok();
+                }
+            }
+        }
+    }
+
+    pub fn save<S: Storage>(&mut self, store: &mut Store<S>) -> Result<(), store::Error> {
+        let slot = match self.unsaved {
+            Some(slot) => slot,
+            None => return Ok(()),
+        };
+
+        let mut bytes = [0; 64 * STEP];
+        for (step, chunk) in self.slots[slot].iter().zip(bytes.chunks_exact_mut(STEP)) {
+            chunk.copy_from_slice(&step.to_bytes());
+        }
+        let len = self.slots[slot].len() * STEP;
+        store.set(key::MACROS + slot as u8, VERSION, &bytes[..len])?;
+        self.unsaved = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Ram;
+    use keyberon::key_code::KeyCode::{self, *};
+
+    // What the host gets at each millisecond of the recording.
+    fn typed(now: u32) -> &'static [KeyCode] {
+        match now {
+            100..=119 => &[LShift],
+            120..=149 => &[LShift, A],
+            150..=159 => &[LShift],
+            170..=189 => &[B],
+            _ => &[],
+        }
+    }
+
+    fn report(keys: &[KeyCode]) -> KbHidReport {
+        keys.iter().copied().collect()
+    }
+
+    fn recorded(until: u32) -> Macros {
+        let mut macros = Macros::default();
+        assert!(macros.start_recording(0, 0));
+        for now in 0..until {
+            macros.record(now, &report(typed(now)));
+        }
+        macros.stop_recording();
+        macros
+    }
+
+    fn played(macros: &Macros) -> KbHidReport {
+        let mut report = KbHidReport::default();
+        for code in macros.keys() {
+            report.pressed_code(*code);
+        }
+        report
+    }
+
+    #[test]
+    fn plays_back_what_was_typed() {
+        let mut macros = recorded(300);
+        macros.play(0);
+        for now in 0..300 {
+            macros.tick();
+            assert_eq!(played(&macros), report(typed(now)), "at {}", now);
+        }
+        assert!(macros.playback.is_none());
+    }
+
+    #[test]
+    fn keys_held_at_the_stop_are_released() {
+        let mut macros = recorded(180);
+        assert_eq!(
+            macros.slots[0].last(),
+            Some(&Step {
+                code: B as u8,
+                press: false,
+                delay: 0
+            })
+        );
+        macros.play(0);
+        for _ in 0..200 {
+            macros.tick();
+        }
+        assert_eq!(macros.keys(), &[]);
+    }
+
+    #[test]
+    fn no_recording_while_playing() {
+        let mut macros = recorded(300);
+        macros.play(0);
+        assert!(!macros.start_recording(1, 0));
+        assert!(!macros.is_recording());
+    }
+
+    #[test]
+    fn unsaved_until_written() {
+        let mut macros = recorded(300);
+        // too small for the recording
+        let mut store = Store::new(Ram::new(32));
+        assert!(macros.save(&mut store).is_err());
+        assert_eq!(macros.unsaved, Some(0));
+
+        let mut store = Store::new(Ram::new(1024));
+        assert!(macros.save(&mut store).is_ok());
+        assert_eq!(macros.unsaved, None);
+        assert_eq!(store.get(key::MACROS).map(|(_, s)| s.len()), Some(6 * STEP));
+    }
+
+    #[test]
+    fn steps_round_trip() {
+        let step = Step {
+            code: LShift as u8,
+            press: true,
+            delay: 300,
+        };
+        assert_eq!(Step::from_bytes(&step.to_bytes()), step);
+    }
+}
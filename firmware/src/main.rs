@@ -8,23 +8,46 @@ use rtic::app;
 
 use stm32f4xx_hal as hal;
 
+pub mod caps_word;
 pub mod custom_action;
 pub mod dispatcher;
+pub mod dynamic_keymap;
+pub mod encoder_mode;
+pub mod flash;
+pub mod host_layout;
 pub mod keyboard;
 pub mod keymap;
-pub(crate) mod multi;
+pub mod layer_profile;
+pub mod layer_rules;
+pub mod leader;
+pub mod legend;
 pub mod rotary;
 pub mod serial;
+pub mod switcher;
+pub mod typing;
+
+// see lib.rs
+pub(crate) use peautkb::multi;
+pub use peautkb::{
+    combo, debounce, diagnostics, key_override, macros, one_shot, quadrature, report, settings, store,
+    tap_hold, wpm,
+};
 
 #[app(device = crate::hal::stm32, peripherals = true, dispatchers = [SPI4, SPI5, SPI6])]
 mod app {
 
     use crate::custom_action::*;
+    use crate::debounce::{self, Debouncer};
+    use crate::diagnostics::Detector;
     use crate::dispatcher::display::OLED;
     use crate::dispatcher::*;
+    use crate::dynamic_keymap::Buffer;
+    use crate::flash::Flash;
+    use crate::settings;
+    use crate::store::Store;
     use crate::keyboard::*;
-    use crate::keymap::LAYERS;
-    use crate::rotary::*;
+    use crate::keymap;
+    use crate::rotary::{self, *};
     use crate::serial::*;
 
     use core::convert::Infallible;
@@ -40,11 +63,10 @@ mod app {
 
     use embedded_hal::digital::v2::{InputPin, OutputPin};
     use generic_array::typenum::{U4, U7};
-    use keyberon::debounce::Debouncer;
     use keyberon::hid;
     use keyberon::impl_heterogenous_array;
     use keyberon::layout::{Event, Layout};
-    use keyberon::matrix::{Matrix, PressedKeys};
+    use keyberon::matrix::Matrix;
     use stm32f4xx_hal::dma::StreamsTuple;
 
     use crate::app;
@@ -105,12 +127,14 @@ mod app {
         rx: RxComms,
         dispatcher: Dispatcher,
         matrix: Matrix<app::Cols, app::Rows>,
-        debouncer: Debouncer<PressedKeys<U4, U7>>,
+        debouncer: Debouncer,
+        detector: Detector,
         layout: Layout<PkbAction>,
         initd: bool,
         timer_init: bool,
         rotary: Rotary,
         custom_action_state: CustomActionState,
+        store: Store<Flash>,
     }
 
     static mut EP_MEMORY: [u32; 1024] = [0; 1024];
@@ -118,6 +142,7 @@ mod app {
     #[init]
     fn init(mut c: init::Context) -> (init::LateResources, init::Monotonics) {
         static mut USB_BUS: Option<UsbBusAllocator<otg_fs::UsbBusType>> = None;
+        static mut KEYMAP: [Buffer; 2] = [Buffer::EMPTY, Buffer::EMPTY];
         let mut perfs: stm32::Peripherals = c.device;
 
         let rcc = perfs.RCC.constrain();
@@ -131,6 +156,13 @@ mod app {
 
         let mono = DwtSystick::new(&mut c.core.DCB, c.core.DWT, c.core.SYST, clocks.hclk().0);
 
+        // Erasing stalls the cpu, the store's spare sector is erased before
+        // USB or the timers have started.
+        let mut store = Store::new(Flash::new(perfs.FLASH));
+        if store.erase_spare().is_err() {
+            defmt::error!("failed to erase the store");
+        }
+
         let gpioa = perfs.GPIOA.split();
         let gpiob = perfs.GPIOB.split();
 
@@ -151,14 +183,19 @@ mod app {
         let display = OLED::new(perfs.I2C1, pb6, pb7, clocks);
 
         // Rotary encoder pins
-        let pb4 = gpiob.pb4.into_pull_up_input();
+        let mut pb4 = gpiob.pb4.into_pull_up_input();
         let mut pb5 = gpiob.pb5.into_pull_up_input();
 
-        pb5.make_interrupt_source(&mut syscfg);
-        pb5.enable_interrupt(&mut perfs.EXTI);
-        pb5.trigger_on_edge(&mut perfs.EXTI, Edge::RISING_FALLING);
+        if keymap::ENCODER.sampling == Sampling::Interrupt {
+            pb4.make_interrupt_source(&mut syscfg);
+            pb4.enable_interrupt(&mut perfs.EXTI);
+            pb4.trigger_on_edge(&mut perfs.EXTI, Edge::RISING_FALLING);
+            pb5.make_interrupt_source(&mut syscfg);
+            pb5.enable_interrupt(&mut perfs.EXTI);
+            pb5.trigger_on_edge(&mut perfs.EXTI, Edge::RISING_FALLING);
+        }
 
-        let rotary = Rotary::new(pb4, pb5, (3, 0), (3, 1));
+        let rotary = Rotary::new(pb4, pb5, keymap::ENCODER);
 
         // USB keyboard
         let usb = otg_fs::USB {
@@ -205,15 +242,23 @@ mod app {
         )
         .unwrap();
 
-        let debouncer = Debouncer::new(PressedKeys::default(), PressedKeys::default(), 5);
+        let debouncer = Debouncer::new(keymap::DEBOUNCE);
+
+        let mut custom_action_state = CustomActionState::new(KEYMAP);
+        custom_action_state.load_macros(&store);
+        custom_action_state.load_keymap(&store);
 
-        let layout = Layout::new(LAYERS);
+        let layout = Layout::new(custom_action_state.layers());
 
         let steams = StreamsTuple::new(perfs.DMA1);
         let stream = steams.4;
 
         let leds = leds::LEDs::new(perfs.SPI2, gpiob.pb15.into_alternate_af5(), clocks, stream);
 
+        let mut dispatcher = Dispatcher::new(display, leds);
+        dispatcher.load_stats(&store);
+        dispatcher.set_layers(custom_action_state.layers());
+
         ping::spawn_after(Milliseconds::new(4000_u32)).ok();
 
         (
@@ -224,14 +269,16 @@ mod app {
                 usb_dev,
                 tx,
                 rx,
-                dispatcher: Dispatcher::new(display, leds),
+                dispatcher,
                 initd: false,
                 matrix,
                 debouncer,
+                detector: Detector::default(),
                 layout,
                 timer_init: false,
                 rotary,
-                custom_action_state: CustomActionState::new(),
+                custom_action_state,
+                store,
             },
             init::Monotonics(mono),
         )
@@ -261,32 +308,32 @@ mod app {
                 rx.lock(|rx| {
                     if let Some(message) = rx.read_event() {
                         dispatch_event::spawn(message).ok();
-                        match message {
-                            Message::SecondaryKeyPress(i, j) => {
-                                layout.lock(|l| {
-                                    l.event(Event::Press(i, j));
-                                    custom_action_state.lock(|c| {
-                                        let messages = c.process(l.tick());
-                                        for m in messages.into_iter() {
-                                            dispatch_event::spawn(m).ok();
-                                        }
-                                    });
-                                });
-                                send_hid_report::spawn().ok();
+                        let event = match message {
+                            Message::SecondaryKeyPress(i, j) => Some(Event::Press(i, j)),
+                            Message::SecondaryKeyRelease(i, j) => Some(Event::Release(i, j)),
+                            Message::SecondaryEncoderPress(e, d) => {
+                                let (i, j) = rotary::coord(e, d);
+                                Some(Event::Press(i, j))
                             }
-                            Message::SecondaryKeyRelease(i, j) => {
-                                layout.lock(|l| {
-                                    l.event(Event::Release(i, j));
-                                    custom_action_state.lock(|c| {
-                                        let messages = c.process(l.tick());
-                                        for m in messages.into_iter() {
-                                            dispatch_event::spawn(m).ok();
-                                        }
-                                    });
-                                });
-                                send_hid_report::spawn().ok();
+                            Message::SecondaryEncoderRelease(e, d) => {
+                                let (i, j) = rotary::coord(e, d);
+                                Some(Event::Release(i, j))
                             }
-                            _ => (),
+                            _ => None,
+                        };
+                        if let Some(event) = event {
+                            layout.lock(|l| {
+                                custom_action_state.lock(|c| {
+                                    for m in c.event(l, event) {
+                                        dispatch_event::spawn(m).ok();
+                                    }
+                                    let messages = c.process(l.tick());
+                                    for m in messages.into_iter() {
+                                        dispatch_event::spawn(m).ok();
+                                    }
+                                });
+                            });
+                            send_hid_report::spawn().ok();
                         }
                     }
                 });
@@ -306,6 +353,9 @@ mod app {
                 if dev.poll(&mut [mk]) {
                     mk.poll();
                 }
+                if let Some(m) = mk.device_mut().take_keymap_request() {
+                    dispatch_event::spawn(m).ok();
+                }
             })
         });
         initd.lock(|b| {
@@ -331,50 +381,33 @@ mod app {
         });
     }
 
-    #[task(binds = EXTI9_5, priority = 2, resources = [rotary, layout, custom_action_state])]
-    fn rot5(c: rot5::Context) {
-        let rot5::Resources {
-            mut rotary,
-            mut layout,
-            mut custom_action_state,
-        } = c.resources;
-        let mut dirty = false;
-        layout.lock(|l| {
-            rotary.lock(|r| {
-                r.clear_interrupt();
-                for event in r.poll() {
-                    dirty = true;
-                    l.event(event);
-                    match event {
-                        Event::Press(i, j) => {
-                            dispatch_event::spawn(Message::MatrixKeyPress(i, j)).ok();
-                        }
-                        Event::Release(i, j) => {
-                            dispatch_event::spawn(Message::MatrixKeyRelease(i, j)).ok();
-                        }
-                    }
-                    custom_action_state.lock(|c| {
-                        let messages = c.process(l.tick());
-                        for m in messages.into_iter() {
-                            dispatch_event::spawn(m).ok();
-                        }
-                    });
-                }
-            });
+    // Either pin of the encoder changed, the scan presses its keys.
+    #[task(binds = EXTI4, priority = 2, resources = [rotary])]
+    fn rot4(c: rot4::Context) {
+        let rot4::Resources { mut rotary } = c.resources;
+        rotary.lock(|r| {
+            r.clear_interrupt();
+            r.sample();
         });
+    }
 
-        if dirty {
-            send_hid_report::spawn().ok();
-        }
+    #[task(binds = EXTI9_5, priority = 2, resources = [rotary])]
+    fn rot5(c: rot5::Context) {
+        let rot5::Resources { mut rotary } = c.resources;
+        rotary.lock(|r| {
+            r.clear_interrupt();
+            r.sample();
+        });
     }
 
     #[task(binds = TIM3,
             priority = 3,
-            resources = [scan_timer, debouncer, matrix, layout, custom_action_state, rotary])]
+            resources = [scan_timer, debouncer, detector, matrix, layout, custom_action_state, rotary])]
     fn scan(c: scan::Context) {
         let scan::Resources {
             mut scan_timer,
             mut debouncer,
+            mut detector,
             mut matrix,
             mut layout,
             mut custom_action_state,
@@ -385,37 +418,51 @@ mod app {
         let mut dirty = false;
 
         let pressed_keys = matrix.lock(|m| m.get().unwrap());
+        let mut read: debounce::Keys = Default::default();
+        for (i, j) in pressed_keys.iter_pressed() {
+            read[i][j] = true;
+        }
         layout.lock(|l| {
-            debouncer.lock(|d| {
-                rotary.lock(|r| {
-                    for event in d.events(pressed_keys).chain(r.release()) {
-                        dirty = true;
-                        l.event(event);
-                        match event {
-                            Event::Press(i, j) => {
-                                dispatch_event::spawn(Message::MatrixKeyPress(i, j)).ok();
+            custom_action_state.lock(|c| {
+                debouncer.lock(|d| {
+                    rotary.lock(|r| {
+                        for event in d.events(read).chain(r.tick()) {
+                            dirty = true;
+                            for m in c.event(l, event) {
+                                dispatch_event::spawn(m).ok();
                             }
-                            Event::Release(i, j) => {
-                                dispatch_event::spawn(Message::MatrixKeyRelease(i, j)).ok();
+                            match event {
+                                Event::Press(i, j) => {
+                                    dispatch_event::spawn(Message::MatrixKeyPress(i, j)).ok();
+                                }
+                                Event::Release(i, j) => {
+                                    dispatch_event::spawn(Message::MatrixKeyRelease(i, j)).ok();
+                                }
                             }
                         }
-                    }
-                    custom_action_state.lock(|c| {
-                        let messages = c.process(l.tick());
+                    })
+                });
+                for m in c.tick(l) {
+                    dispatch_event::spawn(m).ok();
+                }
 
-                        for m in messages.into_iter() {
-                            dispatch_event::spawn(m).ok();
-                        }
-                    });
-                    custom_action_state.lock(|c| {
-                        for m in c.check_layout_for_events(l) {
-                            dispatch_event::spawn(m).ok();
-                        }
-                    });
-                })
+                let messages = c.process(l.tick());
+
+                for m in messages.into_iter() {
+                    dispatch_event::spawn(m).ok();
+                }
+
+                for m in c.check_layout_for_events(l) {
+                    dispatch_event::spawn(m).ok();
+                }
             });
         });
 
+        // after the keys, so a chattering switch doesn't hold up typing
+        if let Some(report) = detector.lock(|g| g.scan(&read)) {
+            dispatch_event::spawn(Message::Diagnostic(report)).ok();
+        }
+
         send_hid_report::spawn().ok();
     }
 
@@ -454,7 +501,7 @@ mod app {
         }
     }
 
-    #[task(resources = [dispatcher, tx, timer_init, scan_timer, tick_timer, layout], priority = 1, capacity = 30)]
+    #[task(resources = [dispatcher, tx, timer_init, scan_timer, tick_timer, layout, custom_action_state, store, debouncer], priority = 1, capacity = 30)]
     fn dispatch_event(c: dispatch_event::Context, message: Message) {
         let dispatch_event::Resources {
             mut dispatcher,
@@ -463,8 +510,96 @@ mod app {
             mut scan_timer,
             mut tick_timer,
             mut layout,
+            mut custom_action_state,
+            mut store,
+            mut debouncer,
         } = c.resources;
 
+        match message {
+            Message::InitTimers => timer_init.lock(|t| {
+                if !*t {
+                    scan_timer.lock(|t| t.listen(timer::Event::TimeOut));
+                    tick_timer.lock(|t| t.listen(timer::Event::TimeOut));
+                    *t = true;
+                }
+            }),
+            Message::SetDefaultLayer(i) => {
+                layout.lock(|l| l.set_default_layer(i));
+                custom_action_state.lock(|c| c.set_default_layer(i));
+            }
+            Message::TapHold(a) => {
+                custom_action_state.lock(|c| c.adjust_tap_hold(a));
+            }
+            // each half debounces its own keys, the same way
+            Message::Debounce(a) => {
+                let config = debouncer.lock(|d| {
+                    d.apply(a);
+                    d.config()
+                });
+                tx.lock(|t| t.send_event(Message::SecondaryDebounce(config)));
+                dispatch_event::spawn(Message::SaveSetting(Setting::Debounce(config))).ok();
+            }
+            Message::SecondaryDebounce(config) => {
+                debouncer.lock(|d| d.set_config(config));
+                dispatch_event::spawn(Message::SaveSetting(Setting::Debounce(config))).ok();
+            }
+            Message::LoadSetting(Setting::Debounce(config)) => {
+                debouncer.lock(|d| d.set_config(config));
+            }
+            Message::DisplaySelect(s) => {
+                custom_action_state.lock(|c| c.set_menu_open(s == DisplayedState::Menu));
+            }
+            Message::LoadSetting(Setting::EncoderMode(mode)) => {
+                custom_action_state.lock(|c| c.set_encoder_mode(mode));
+            }
+            Message::Recording(None) => {
+                save_macros::spawn().ok();
+            }
+            Message::SaveStats => {
+                save_stats::spawn().ok();
+            }
+            Message::Idle | Message::Sleep => {
+                erase_store::spawn().ok();
+            }
+            Message::Remap(true) => {
+                custom_action_state.lock(|c| c.start_remap());
+            }
+            Message::EditKey(edit) | Message::SecondaryEditKey(edit) => {
+                // the dispatcher lets go of the old layers before the next edit
+                if let Some(layers) =
+                    layout.lock(|l| custom_action_state.lock(|c| c.edit_key(l, edit)))
+                {
+                    dispatcher.lock(|d| d.set_layers(layers));
+                }
+                save_keymap::spawn().ok();
+            }
+            Message::ResetKeymap | Message::SecondaryResetKeymap => {
+                let layers = layout.lock(|l| custom_action_state.lock(|c| c.reset_keymap(l)));
+                dispatcher.lock(|d| d.set_layers(layers));
+                save_keymap::spawn().ok();
+            }
+            Message::LateInit => store.lock(|s| {
+                for setting in settings::load(s) {
+                    dispatch_event::spawn(Message::LoadSetting(setting)).ok();
+                }
+            }),
+            Message::SaveSetting(setting) => store.lock(|s| {
+                if settings::save(s, setting).is_err() {
+                    defmt::error!("failed to save a setting");
+                }
+            }),
+            Message::FactoryReset | Message::SecondaryFactoryReset => {
+                custom_action_state.lock(|c| c.set_encoder_mode(Default::default()));
+                debouncer.lock(|d| d.set_config(keymap::DEBOUNCE));
+                store.lock(|s| {
+                    if settings::reset(s).is_err() {
+                        defmt::error!("failed to reset the settings");
+                    }
+                })
+            }
+            _ => (),
+        }
+
         dispatcher.lock(|d| {
             if message == Message::UpdateDisplay {
                 d.update_display();
@@ -474,25 +609,70 @@ mod app {
                 .for_each(|t| match t {
                     MessageType::Local(m) => {
                         dispatch_event::spawn(m).ok();
-                        match m {
-                            Message::InitTimers => timer_init.lock(|t| {
-                                if !*t {
-                                    scan_timer.lock(|t| t.listen(timer::Event::TimeOut));
-                                    tick_timer.lock(|t| t.listen(timer::Event::TimeOut));
-                                    *t = true;
-                                }
-                            }),
-                            Message::SetDefaultLayer(i) => {
-                                layout.lock(|l| l.set_default_layer(i));
-                            }
-                            _ => (),
-                        }
                     }
                     MessageType::Remote(m) => tx.lock(|t| t.send_event(m)),
                 })
         });
     }
 
+    /// Only runs when a recording stops.
+    #[task(resources = [store, custom_action_state])]
+    fn save_macros(c: save_macros::Context) {
+        let save_macros::Resources {
+            mut store,
+            mut custom_action_state,
+        } = c.resources;
+
+        store.lock(|s| {
+            if custom_action_state.lock(|c| c.save_macros(s)).is_err() {
+                defmt::error!("failed to save macros");
+            }
+        });
+    }
+
+    /// Like saving macros, only runs once the keymap has been edited.
+    #[task(resources = [store, custom_action_state])]
+    fn save_keymap(c: save_keymap::Context) {
+        let save_keymap::Resources {
+            mut store,
+            mut custom_action_state,
+        } = c.resources;
+
+        store.lock(|s| {
+            if custom_action_state.lock(|c| c.save_keymap(s)).is_err() {
+                defmt::error!("failed to save keymap");
+            }
+        });
+    }
+
+    /// Key press counts, once typing has stopped for a while.
+    #[task(resources = [store, dispatcher])]
+    fn save_stats(c: save_stats::Context) {
+        let save_stats::Resources {
+            mut store,
+            mut dispatcher,
+        } = c.resources;
+
+        store.lock(|s| {
+            if dispatcher.lock(|d| d.save_stats(s)).is_err() {
+                defmt::error!("failed to save stats");
+            }
+        });
+    }
+
+    /// Erasing stalls the cpu for a second or more, so the sector the store
+    /// compacts into next is only erased once nobody is typing.
+    #[task(resources = [store])]
+    fn erase_store(c: erase_store::Context) {
+        let erase_store::Resources { mut store } = c.resources;
+
+        store.lock(|s| {
+            if s.erase_spare().is_err() {
+                defmt::error!("failed to erase the store");
+            }
+        });
+    }
+
     #[task(resources = [tx, initd])]
     fn ping(c: ping::Context) {
         defmt::info!("Pinging ... ");
@@ -0,0 +1,422 @@
+use heapless::{
+    consts::{U4, U8},
+    Vec,
+};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+use serde::{Deserialize, Serialize};
+
+/// What to feed the layout, in order, in place of an event.
+pub type Events = Vec<Event, U4>;
+
+/// `L` is the keymap's layer, see `keymap::one_shot_for`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Key<L> {
+    Mod(KeyCode),
+    Layer(L),
+}
+
+/// Timings are in milliseconds, i.e. scan ticks. Tapping a one-shot key
+/// again before it times out locks it, until it is tapped once more.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub mod_timeout: u16,
+    pub layer_timeout: u16,
+}
+
+/// Modifiers are HID modifier bits, as in the report.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Status<L> {
+    pub mods: u8,
+    pub locked_mods: u8,
+    pub layer: Option<L>,
+    pub layer_locked: bool,
+}
+
+impl<L> Default for Status<L> {
+    fn default() -> Self {
+        Status {
+            mods: 0,
+            locked_mods: 0,
+            layer: None,
+            layer_locked: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Trigger {
+    Coord(u8, u8),
+    Tap(KeyCode),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Pending(u32),
+    Used(Trigger),
+    Locked,
+}
+
+#[derive(Copy, Clone)]
+struct Shot<L> {
+    key: Key<L>,
+    coord: (u8, u8),
+    state: State,
+}
+
+#[derive(Copy, Clone)]
+struct Down<L> {
+    key: Key<L>,
+    coord: (u8, u8),
+    interrupted: bool,
+    arm: bool,
+}
+
+/// One-shot keys are bound with their hold action, so while one is held
+/// the layout handles it like any other key. Released without another key
+/// being pressed in between, it stays armed for the next key: modifiers
+/// through the report, layers by pressing the one-shot key again in the
+/// layout around the next key.
+pub struct OneShot<L> {
+    config: Config,
+    shots: Vec<Shot<L>, U8>,
+    down: Vec<Down<L>, U4>,
+}
+
+impl<L: Copy + PartialEq> OneShot<L> {
+    pub fn new(config: Config) -> Self {
+        OneShot {
+            config,
+            shots: Vec::new(),
+            down: Vec::new(),
+        }
+    }
+
+    /// `key` is the one-shot key at the coordinate of a press, if it is one.
+    pub fn event(&mut self, now: u32, event: Event, key: Option<Key<L>>) -> Events {
+        let mut events = Events::new();
+        match (event, key) {
+            (Event::Press(i, j), Some(key)) => {
+                let arm = match self.position(key) {
+                    Some(k) => self.press_again(now, k, (i, j), &mut events),
+                    None => true,
+                };
+                self.down
+                    .push(Down {
+                        key,
+                        coord: (i, j),
+                        interrupted: false,
+                        arm,
+                    })
+                    .ok();
+                events.push(event).ok();
+            }
+            (Event::Press(i, j), None) => {
+                for down in self.down.iter_mut() {
+                    down.interrupted = true;
+                }
+                for shot in self.shots.iter_mut() {
+                    if let State::Pending(_) = shot.state {
+                        shot.state = State::Used(Trigger::Coord(i, j));
+                        if let Key::Layer(_) = shot.key {
+                            events.push(Event::Press(shot.coord.0, shot.coord.1)).ok();
+                        }
+                    }
+                }
+                events.push(event).ok();
+            }
+            (Event::Release(i, j), _) => match self.down.iter().position(|d| d.coord == (i, j)) {
+                Some(k) => {
+                    let down = self.down.swap_remove(k);
+                    self.release(now, down, &mut events);
+                }
+                None => {
+                    events.push(event).ok();
+                    self.used_up(Trigger::Coord(i, j), &mut events);
+                }
+            },
+        }
+        events
+    }
+
+    /// A tap-hold key was tapped, it takes the pending shots with it like
+    /// any other key. Its tap should have been looked up on `layer()`.
+    pub fn tap_press(&mut self, kc: KeyCode) -> Events {
+        let mut events = Events::new();
+        for shot in self.shots.iter_mut() {
+            if let State::Pending(_) = shot.state {
+                shot.state = State::Used(Trigger::Tap(kc));
+                if let Key::Layer(_) = shot.key {
+                    events.push(Event::Press(shot.coord.0, shot.coord.1)).ok();
+                }
+            }
+        }
+        events
+    }
+
+    pub fn tap_release(&mut self, kc: KeyCode) -> Events {
+        let mut events = Events::new();
+        self.used_up(Trigger::Tap(kc), &mut events);
+        events
+    }
+
+    pub fn tick(&mut self, now: u32) {
+        let config = self.config;
+        let mut k = 0;
+        while k < self.shots.len() {
+            let shot = self.shots[k];
+            let timeout = match shot.key {
+                Key::Mod(_) => config.mod_timeout,
+                Key::Layer(_) => config.layer_timeout,
+            };
+            match shot.state {
+                State::Pending(since) if now.wrapping_sub(since) > timeout as u32 => {
+                    self.shots.swap_remove(k);
+                }
+                _ => k += 1,
+            }
+        }
+    }
+
+    pub fn mods(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        self.shots.iter().filter_map(|s| match s.key {
+            Key::Mod(kc) => Some(kc),
+            Key::Layer(_) => None,
+        })
+    }
+
+    /// The pending one-shot layer, the next key is on it although the
+    /// layout doesn't hold it yet.
+    pub fn layer(&self) -> Option<L> {
+        self.shots.iter().find_map(|s| match (s.key, s.state) {
+            (Key::Layer(layer), State::Pending(_)) => Some(layer),
+            _ => None,
+        })
+    }
+
+    pub fn status(&self) -> Status<L> {
+        let mut status = Status::default();
+        for shot in self.shots.iter() {
+            let locked = shot.state == State::Locked;
+            match shot.key {
+                Key::Mod(kc) => {
+                    let bit = match kc.is_modifier() {
+                        true => 1 << (kc as u8 - KeyCode::LCtrl as u8),
+                        false => 0,
+                    };
+                    status.mods |= bit;
+                    if locked {
+                        status.locked_mods |= bit;
+                    }
+                }
+                Key::Layer(layer) => {
+                    status.layer = Some(layer);
+                    status.layer_locked = locked;
+                }
+            }
+        }
+        status
+    }
+
+    fn position(&self, key: Key<L>) -> Option<usize> {
+        self.shots.iter().position(|s| s.key == key)
+    }
+
+    // Returns whether the key arms again once released.
+    fn press_again(&mut self, now: u32, k: usize, coord: (u8, u8), events: &mut Events) -> bool {
+        let shot = self.shots[k];
+        let timeout = match shot.key {
+            Key::Mod(_) => self.config.mod_timeout,
+            Key::Layer(_) => self.config.layer_timeout,
+        };
+        match shot.state {
+            State::Locked => {
+                // a locked layer is still held in the layout
+                if let Key::Layer(_) = shot.key {
+                    events.push(Event::Release(shot.coord.0, shot.coord.1)).ok();
+                }
+                self.shots.swap_remove(k);
+                false
+            }
+            State::Pending(since) if now.wrapping_sub(since) <= timeout as u32 => {
+                self.shots[k].state = State::Locked;
+                self.shots[k].coord = coord;
+                false
+            }
+            // still applied to a held key, it goes once that is released
+            _ => true,
+        }
+    }
+
+    fn release(&mut self, now: u32, down: Down<L>, events: &mut Events) {
+        let (i, j) = down.coord;
+        let locked = self
+            .shots
+            .iter()
+            .any(|s| s.key == down.key && s.coord == down.coord && s.state == State::Locked);
+
+        // keeping the layer key held in the layout is what locks it
+        if !(locked && matches!(down.key, Key::Layer(_))) {
+            events.push(Event::Release(i, j)).ok();
+        }
+
+        if down.arm && !down.interrupted {
+            self.shots
+                .push(Shot {
+                    key: down.key,
+                    coord: down.coord,
+                    state: State::Pending(now),
+                })
+                .ok();
+        }
+    }
+
+    fn used_up(&mut self, trigger: Trigger, events: &mut Events) {
+        let mut k = 0;
+        while k < self.shots.len() {
+            let shot = self.shots[k];
+            if shot.state == State::Used(trigger) {
+                if let Key::Layer(_) = shot.key {
+                    events.push(Event::Release(shot.coord.0, shot.coord.1)).ok();
+                }
+                self.shots.swap_remove(k);
+            } else {
+                k += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use Event::{Press, Release};
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    enum Layer {
+        Sym,
+    }
+
+    const CONFIG: Config = Config {
+        mod_timeout: 1000,
+        layer_timeout: 1000,
+    };
+    const SHIFT: (u8, u8) = (3, 0);
+    const SYM: (u8, u8) = (3, 1);
+
+    fn key(coord: (u8, u8)) -> Option<Key<Layer>> {
+        match coord {
+            SHIFT => Some(Key::Mod(KeyCode::LShift)),
+            SYM => Some(Key::Layer(Layer::Sym)),
+            _ => None,
+        }
+    }
+
+    fn press(os: &mut OneShot<Layer>, now: u32, (i, j): (u8, u8)) -> Events {
+        os.event(now, Press(i, j), key((i, j)))
+    }
+
+    fn release(os: &mut OneShot<Layer>, now: u32, (i, j): (u8, u8)) -> Events {
+        os.event(now, Release(i, j), None)
+    }
+
+    fn tap(os: &mut OneShot<Layer>, now: u32, coord: (u8, u8)) -> Events {
+        let mut events = press(os, now, coord);
+        events.extend_from_slice(&release(os, now + 10, coord)).ok();
+        events
+    }
+
+    fn mods(os: &OneShot<Layer>) -> Vec<KeyCode> {
+        os.mods().collect()
+    }
+
+    #[test]
+    fn mod_shot() {
+        let mut os = OneShot::new(CONFIG);
+        assert_eq!(&tap(&mut os, 0, SHIFT)[..], &[Press(3, 0), Release(3, 0)]);
+        assert_eq!(mods(&os), &[KeyCode::LShift]);
+        assert_eq!(os.status().mods, 0x02);
+
+        assert_eq!(&press(&mut os, 100, (1, 1))[..], &[Press(1, 1)]);
+        assert_eq!(mods(&os), &[KeyCode::LShift]);
+        assert_eq!(&release(&mut os, 150, (1, 1))[..], &[Release(1, 1)]);
+        assert_eq!(mods(&os), &[]);
+        assert_eq!(os.status(), Status::default());
+    }
+
+    #[test]
+    fn layer_shot() {
+        let mut os = OneShot::new(CONFIG);
+        assert_eq!(&tap(&mut os, 0, SYM)[..], &[Press(3, 1), Release(3, 1)]);
+        assert_eq!(os.layer(), Some(Layer::Sym));
+
+        assert_eq!(
+            &press(&mut os, 100, (1, 1))[..],
+            &[Press(3, 1), Press(1, 1)]
+        );
+        assert_eq!(os.layer(), None);
+        assert_eq!(
+            &release(&mut os, 150, (1, 1))[..],
+            &[Release(1, 1), Release(3, 1)]
+        );
+        assert_eq!(os.status(), Status::default());
+    }
+
+    #[test]
+    fn double_tap_lock() {
+        let mut os = OneShot::new(CONFIG);
+        tap(&mut os, 0, SYM);
+        // the layer stays held in the layout
+        assert_eq!(&tap(&mut os, 100, SYM)[..], &[Press(3, 1)]);
+        assert!(os.status().layer_locked);
+
+        for now in [200, 300].iter() {
+            assert_eq!(&press(&mut os, *now, (1, 1))[..], &[Press(1, 1)]);
+            assert_eq!(&release(&mut os, now + 50, (1, 1))[..], &[Release(1, 1)]);
+        }
+        assert!(os.status().layer_locked);
+
+        assert_eq!(
+            &tap(&mut os, 400, SYM)[..],
+            &[Release(3, 1), Press(3, 1), Release(3, 1)]
+        );
+        assert_eq!(os.status(), Status::default());
+    }
+
+    #[test]
+    fn timeout() {
+        let mut os = OneShot::new(CONFIG);
+        tap(&mut os, 0, SHIFT);
+        tap(&mut os, 0, SYM);
+        os.tick(1010);
+        assert_eq!(mods(&os), &[KeyCode::LShift]);
+        os.tick(1011);
+        assert_eq!(mods(&os), &[]);
+        assert_eq!(os.layer(), None);
+        assert_eq!(&press(&mut os, 1100, (1, 1))[..], &[Press(1, 1)]);
+    }
+
+    #[test]
+    fn tap_hold_interaction() {
+        let mut os = OneShot::new(CONFIG);
+        tap(&mut os, 0, SHIFT);
+        tap(&mut os, 20, SYM);
+
+        // a tap takes both shots, the layer is held around it like around
+        // any other key
+        assert_eq!(&os.tap_press(KeyCode::A)[..], &[Press(3, 1)]);
+        assert_eq!(os.layer(), None);
+        assert_eq!(mods(&os), &[KeyCode::LShift]);
+        assert_eq!(&os.tap_release(KeyCode::A)[..], &[Release(3, 1)]);
+        assert_eq!(mods(&os), &[]);
+        assert_eq!(os.status(), Status::default());
+
+        // releasing another tap leaves the next shot alone
+        tap(&mut os, 200, SHIFT);
+        assert_eq!(&os.tap_release(KeyCode::B)[..], &[]);
+        assert_eq!(mods(&os), &[KeyCode::LShift]);
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+
+/// How many transitions of the two pins make up one detent.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum StepMode {
+    /// A whole quadrature cycle, four transitions.
+    FullStep,
+    /// Half a cycle, the encoder rests with both pins high and both low.
+    HalfStep,
+    /// Every transition.
+    QuarterStep,
+}
+
+impl StepMode {
+    fn transitions(self) -> i8 {
+        match self {
+            StepMode::FullStep => 4,
+            StepMode::HalfStep => 2,
+            StepMode::QuarterStep => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Direction {
+    CW,
+    ACW,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+enum Transition {
+    Stay,
+    Step(Direction),
+    // both pins changed, a transition was missed
+    Invalid,
+}
+
+use Direction::*;
+use Transition::*;
+
+// Indexed by `previous << 2 | next`, with the pins as `a << 1 | b`.
+// Clockwise goes 00, 10, 11, 01, counter-clockwise the other way round.
+#[rustfmt::skip]
+const TRANSITIONS: [Transition; 16] = [
+    Stay,      Step(ACW), Step(CW),  Invalid,
+    Step(CW),  Stay,      Invalid,   Step(ACW),
+    Step(ACW), Invalid,   Stay,      Step(CW),
+    Invalid,   Step(CW),  Step(ACW), Stay,
+];
+
+/// Turns samples of the two pins into detents. A bounce steps back and
+/// forth, which cancels out, and a jump of both pins at once is dropped
+/// along with the transitions counted towards the next detent.
+///
+/// A detent is only sent once the pins are back where the encoder rests,
+/// and the count starts over there, so a missed transition never leaves
+/// the detents out of step with the clicks.
+pub struct Decoder {
+    mode: StepMode,
+    state: u8,
+    // the pins at a detent, the encoder is resting on one at power up
+    rest: u8,
+    // towards the next detent, counter-clockwise is negative
+    count: i8,
+}
+
+impl Decoder {
+    pub fn new(mode: StepMode, a: bool, b: bool) -> Self {
+        Decoder {
+            mode,
+            state: pins(a, b),
+            rest: pins(a, b),
+            count: 0,
+        }
+    }
+
+    fn at_rest(&self) -> bool {
+        match self.mode {
+            StepMode::FullStep => self.state == self.rest,
+            StepMode::HalfStep => self.state == self.rest || self.state == self.rest ^ 0b11,
+            StepMode::QuarterStep => true,
+        }
+    }
+
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Direction> {
+        let next = pins(a, b);
+        let transition = TRANSITIONS[((self.state << 2) | next) as usize];
+        self.state = next;
+
+        match transition {
+            Stay => None,
+            Invalid => {
+                self.count = 0;
+                None
+            }
+            Step(d) => {
+                self.count += match d {
+                    CW => 1,
+                    ACW => -1,
+                };
+                if !self.at_rest() {
+                    return None;
+                }
+                let count = core::mem::replace(&mut self.count, 0);
+                if count.abs() < self.mode.transitions() {
+                    return None;
+                }
+                Some(d)
+            }
+        }
+    }
+}
+
+fn pins(a: bool, b: bool) -> u8 {
+    ((a as u8) << 1) | b as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(false, false), (true, false), (true, true), (false, true)];
+
+    // the pins `steps` transitions on from `from`, counter-clockwise if
+    // negative
+    fn turn(from: usize, steps: i32) -> (usize, (bool, bool)) {
+        let at = (from as i32 + steps).rem_euclid(4) as usize;
+        (at, CLOCKWISE[at])
+    }
+
+    // feeds the decoder the pins one transition at a time
+    fn feed(decoder: &mut Decoder, at: &mut usize, steps: &[i32]) -> std::vec::Vec<Direction> {
+        let mut detents = std::vec::Vec::new();
+        for step in steps.iter() {
+            let (next, (a, b)) = turn(*at, *step);
+            *at = next;
+            detents.extend(decoder.update(a, b));
+        }
+        detents
+    }
+
+    #[test]
+    fn all_transitions() {
+        for previous in 0..4 {
+            for next in 0..4 {
+                let expected = match (next as i32 - previous as i32).rem_euclid(4) {
+                    0 => Stay,
+                    1 => Step(CW),
+                    3 => Step(ACW),
+                    _ => Invalid,
+                };
+                let (a, b) = CLOCKWISE[previous];
+                let (c, d) = CLOCKWISE[next];
+                let index = (pins(a, b) << 2) | pins(c, d);
+                assert_eq!(
+                    TRANSITIONS[index as usize], expected,
+                    "{} to {}",
+                    previous, next
+                );
+            }
+        }
+    }
+
+    // resting with both pins high, the usual detent
+    fn decoder(mode: StepMode) -> (Decoder, usize) {
+        (Decoder::new(mode, true, true), 2)
+    }
+
+    #[test]
+    fn full_detents() {
+        let cases: [(StepMode, &[i32], &[Direction]); 6] = [
+            (StepMode::FullStep, &[1, 1, 1, 1], &[CW]),
+            (StepMode::FullStep, &[-1, -1, -1, -1], &[ACW]),
+            (StepMode::HalfStep, &[1, 1, 1, 1], &[CW, CW]),
+            (StepMode::HalfStep, &[-1, -1], &[ACW]),
+            (StepMode::QuarterStep, &[1, 1, 1], &[CW, CW, CW]),
+            (StepMode::QuarterStep, &[-1, -1], &[ACW, ACW]),
+        ];
+        for (mode, steps, expected) in cases.iter() {
+            let (mut decoder, mut at) = decoder(*mode);
+            assert_eq!(feed(&mut decoder, &mut at, steps), *expected);
+        }
+    }
+
+    #[test]
+    fn reversing_halfway() {
+        let cases: [(StepMode, &[i32], &[Direction]); 4] = [
+            // back to the same detent, then on to the one before it
+            (StepMode::FullStep, &[1, 1, -1, -1, -1, -1, -1, -1], &[ACW]),
+            (StepMode::HalfStep, &[1, -1, -1, -1], &[ACW]),
+            // a bounce on the way
+            (StepMode::FullStep, &[1, -1, 1, 1, 1, -1, 1, 1], &[CW]),
+            (StepMode::QuarterStep, &[1, -1], &[CW, ACW]),
+        ];
+        for (mode, steps, expected) in cases.iter() {
+            let (mut decoder, mut at) = decoder(*mode);
+            assert_eq!(feed(&mut decoder, &mut at, steps), *expected);
+        }
+    }
+
+    #[test]
+    fn double_steps() {
+        let cases: [(StepMode, &[i32], &[Direction]); 6] = [
+            // the detent with a missed transition is dropped, the next one
+            // still needs all four from the detent
+            (StepMode::FullStep, &[1, 2, 1, 1, 1, 1], &[]),
+            (StepMode::FullStep, &[1, 2, 1, 1, 1, 1, 1], &[CW]),
+            (StepMode::FullStep, &[-1, 2, -1, -1, -1, -1, -1], &[ACW]),
+            (StepMode::HalfStep, &[1, 2, 1, 1], &[]),
+            (StepMode::HalfStep, &[2, 1, 1], &[CW]),
+            (StepMode::QuarterStep, &[2, -1], &[ACW]),
+        ];
+        for (mode, steps, expected) in cases.iter() {
+            let (mut decoder, mut at) = decoder(*mode);
+            assert_eq!(feed(&mut decoder, &mut at, steps), *expected, "{:?}", steps);
+        }
+    }
+}
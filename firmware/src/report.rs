@@ -0,0 +1,96 @@
+use keyberon::key_code::KeyCode;
+
+#[derive(Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct KbHidReport([u8; 9]);
+
+impl core::iter::FromIterator<KeyCode> for KbHidReport {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut res = Self::default();
+        for kc in iter {
+            res.pressed(kc);
+        }
+        res
+    }
+}
+
+impl Default for KbHidReport {
+    fn default() -> Self {
+        let mut res = KbHidReport([0; 9]);
+        res.0[0] = 1;
+        res
+    }
+}
+
+impl KbHidReport {
+    /// Returns the byte slice corresponding to the report.
+    pub fn as_bytes(&mut self) -> &[u8] {
+        &self.0
+    }
+
+    /// The non modifier key codes in the report.
+    pub fn keys(&self) -> impl Iterator<Item = u8> + '_ {
+        self.0[3..].iter().copied().filter(|c| *c != 0)
+    }
+
+    /// Add the given key code to the report. If the report is full,
+    /// it will be set to `ErrorRollOver`.
+    pub fn pressed(&mut self, kc: KeyCode) {
+        use KeyCode::*;
+        match kc {
+            No => (),
+            ErrorRollOver | PostFail | ErrorUndefined => self.set_all(kc),
+            _ => self.pressed_code(kc as u8),
+        }
+    }
+
+    /// Every key code in the report, the modifiers as `LCtrl` to `RGui`
+    /// first.
+    pub fn codes(&self) -> impl Iterator<Item = u8> + '_ {
+        let mods = self.0[1];
+        (0..8)
+            .filter(move |bit| mods & 1 << bit != 0)
+            .map(|bit| KeyCode::LCtrl as u8 + bit)
+            .chain(self.keys())
+    }
+
+    /// Adds a key code as `codes` gives them.
+    pub fn pressed_code(&mut self, code: u8) {
+        const LCTRL: u8 = KeyCode::LCtrl as u8;
+        const RGUI: u8 = KeyCode::RGui as u8;
+        match code {
+            LCTRL..=RGUI => self.0[1] |= 1 << (code - LCTRL),
+            _ => self.0[3..]
+                .iter_mut()
+                .find(|c| **c == 0)
+                .map(|c| *c = code)
+                .unwrap_or_else(|| self.set_all(KeyCode::ErrorRollOver)),
+        }
+    }
+
+    pub fn modifiers(&self) -> u8 {
+        self.0[1]
+    }
+
+    pub fn release_modifiers(&mut self, bits: u8) {
+        self.0[1] &= !bits;
+    }
+
+    /// Takes the given, non modifier, key code out of the report.
+    pub fn released(&mut self, code: u8) {
+        let keys = &mut self.0[3..];
+        if let Some(i) = keys.iter().position(|c| *c == code) {
+            keys.copy_within(i + 1.., i);
+            keys[keys.len() - 1] = 0;
+        }
+    }
+
+    fn set_all(&mut self, kc: KeyCode) {
+        for c in &mut self.0[2..] {
+            *c = kc as u8;
+        }
+    }
+}
@@ -1,94 +1,166 @@
 use crate::hal::gpio::{gpiob, Input, PullUp};
+use crate::keymap::ENCODER_ROW;
 use crate::multi::Multi;
+use crate::quadrature::{Decoder, StepMode};
 use embedded_hal::digital::v2::InputPin;
 use keyberon::layout::Event;
+use serde::{Deserialize, Serialize};
 use stm32f4xx_hal::gpio::ExtiPin;
 
+pub use crate::quadrature::Direction;
+
+/// Each half can have one, the primary is the left half.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Encoder {
+    Left,
+    Right,
+}
+
+/// Where turning `encoder` presses on `ENCODER_ROW`, clockwise then
+/// counter-clockwise for each encoder in turn.
+pub fn coord(encoder: Encoder, d: Direction) -> (u8, u8) {
+    let column = match encoder {
+        Encoder::Left => 0,
+        Encoder::Right => 2,
+    };
+    match d {
+        Direction::CW => (ENCODER_ROW, column),
+        Direction::ACW => (ENCODER_ROW, column + 1),
+    }
+}
+
+/// The turn a half's own encoder pressed at `(i, j)`, if it did.
+pub fn turn_at(i: u8, j: u8) -> Option<Direction> {
+    match (i, j) {
+        (ENCODER_ROW, 0) => Some(Direction::CW),
+        (ENCODER_ROW, 1) => Some(Direction::ACW),
+        _ => None,
+    }
+}
+
+/// Timings are in milliseconds, i.e. scan ticks.
+#[derive(Copy, Clone)]
+pub struct Config {
+    pub step_mode: StepMode,
+    pub sampling: Sampling,
+    pub acceleration: Option<Acceleration>,
+}
+
+/// When the pins are read.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Sampling {
+    /// On every edge of either pin.
+    Interrupt,
+    /// Every scan, for encoders too noisy to interrupt on.
+    Timer,
+}
+
+/// Each detent less than `interval` after the last one in the same
+/// direction sends a step more than it, up to `max_steps`.
+#[derive(Copy, Clone)]
+pub struct Acceleration {
+    pub interval: u16,
+    pub max_steps: u8,
+}
+
+/// Turning taps the encoder's keys on `ENCODER_ROW`, so the keymap decides
+/// what a turn does on each layer. Either half's encoder reads as the left
+/// one, the secondary sends its turns over as the right one.
 pub struct Rotary {
     pb4: gpiob::PB4<Input<PullUp>>,
     pb5: gpiob::PB5<Input<PullUp>>,
-    last: (bool, bool),
-    cw_coord: (u8, u8),
-    acw_coord: (u8, u8),
-    release: Multi<Event>,
+    config: Config,
+    decoder: Decoder,
+    now: u32,
+    last_detent: Option<(Direction, u32)>,
+    speed: u8,
+    direction: Direction,
+    pending: u8,
+    held: Option<Direction>,
 }
 
 impl Rotary {
     pub fn new(
         pb4: gpiob::PB4<Input<PullUp>>,
         pb5: gpiob::PB5<Input<PullUp>>,
-        cw_coord: (u8, u8),
-        acw_coord: (u8, u8),
+        config: Config,
     ) -> Self {
+        let decoder = Decoder::new(
+            config.step_mode,
+            pb4.is_high().unwrap(),
+            pb5.is_high().unwrap(),
+        );
         Rotary {
             pb4,
             pb5,
-            last: (false, false),
-            cw_coord,
-            acw_coord,
-            release: Multi::None,
+            config,
+            decoder,
+            now: 0,
+            last_detent: None,
+            speed: 0,
+            direction: Direction::CW,
+            pending: 0,
+            held: None,
         }
     }
 
-    pub fn poll<'a>(&'a mut self) -> impl IntoIterator<Item = Event> + 'a {
-        match self.read_and_debounce() {
-            Some(d) => self.event_for(d),
-            None => Multi::None,
+    /// Reads the pins, a detent is pressed on the next scan.
+    pub fn sample(&mut self) {
+        let (a, b) = (self.pb4.is_high().unwrap(), self.pb5.is_high().unwrap());
+        if let Some(d) = self.decoder.update(a, b) {
+            self.turn(d);
         }
     }
 
-    pub fn event_for(&mut self, d: Direction) -> Multi<Event> {
-        match d {
-            Direction::CW => {
-                self.release
-                    .append(Event::Release(self.cw_coord.0, self.cw_coord.1));
-                Multi::One(Event::Press(self.cw_coord.0, self.cw_coord.1))
-            }
-            Direction::ACW => {
-                self.release
-                    .append(Event::Release(self.acw_coord.0, self.acw_coord.1));
-                Multi::One(Event::Press(self.acw_coord.0, self.acw_coord.1))
+    /// Called every scan, a step is pressed for one tick and released for
+    /// the next, so steps sent by a fast turn each reach the host.
+    pub fn tick(&mut self) -> Multi<Event> {
+        self.now = self.now.wrapping_add(1);
+        if self.config.sampling == Sampling::Timer {
+            self.sample();
+        }
+        match self.held.take() {
+            Some(d) => {
+                let (i, j) = coord(Encoder::Left, d);
+                Multi::One(Event::Release(i, j))
             }
+            None => self.next_press(),
         }
     }
 
-    pub fn release<'a>(&'a mut self) -> impl IntoIterator<Item = Event> + 'a {
-        let out = self.release;
-        self.release = Multi::None;
-        out
-    }
-
     pub fn clear_interrupt(&mut self) {
+        self.pb4.clear_interrupt_pending_bit();
         self.pb5.clear_interrupt_pending_bit();
     }
 
-    fn read_and_debounce(&mut self) -> Option<Direction> {
-        let next = (self.pb4.is_high().unwrap(), self.pb5.is_high().unwrap());
-        match (self.last, next) {
-            ((true, false), (false, true))
-            | ((false, true), (true, false))
-            | ((true, true), (true, false))
-            | ((false, false), (false, true)) => {
-                self.last = next;
-                Some(Direction::ACW)
-            }
-            ((false, false), (true, true))
-            | ((true, true), (false, false))
-            | ((false, true), (false, false))
-            | ((true, false), (true, true)) => {
-                self.last = next;
-                Some(Direction::CW)
-            }
-            _ => {
-                self.last = next;
-                None
+    fn turn(&mut self, d: Direction) {
+        let steps = match (self.config.acceleration, self.last_detent) {
+            (Some(a), Some((last, at)))
+                if last == d && self.now.wrapping_sub(at) < a.interval as u32 =>
+            {
+                (self.speed + 1).min(a.max_steps)
             }
+            _ => 1,
+        };
+        self.speed = steps;
+        self.last_detent = Some((d, self.now));
+
+        if self.direction != d {
+            self.pending = 0;
         }
+        self.direction = d;
+        self.pending = self.pending.saturating_add(steps);
     }
-}
 
-#[derive(Copy, Clone, Eq, PartialEq, Hash)]
-pub enum Direction {
-    CW,
-    ACW,
+    fn next_press(&mut self) -> Multi<Event> {
+        match self.held {
+            None if self.pending > 0 => {
+                self.pending -= 1;
+                self.held = Some(self.direction);
+                let (i, j) = coord(Encoder::Left, self.direction);
+                Multi::One(Event::Press(i, j))
+            }
+            _ => Multi::None,
+        }
+    }
 }
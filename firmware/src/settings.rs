@@ -0,0 +1,176 @@
+use postcard::{from_bytes, to_slice};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::debounce;
+use crate::store::{self, key, Storage, Store};
+
+/// Everything kept across power cycles, each half keeps its own. The LED
+/// mode, the screen and the encoder mode are the firmware's, see
+/// `dispatcher::Setting`.
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Setting<LedMode, Display, EncoderMode> {
+    LedMode(LedMode),
+    Colour(u8, u8, u8),
+    DefaultLayer(u8),
+    Display(Display),
+    // minutes without a key press
+    SleepTimeout(u8),
+    Brightness(u8),
+    EncoderMode(EncoderMode),
+    Debounce(debounce::Config),
+}
+
+// One key per variant, in order.
+const COUNT: u8 = 8;
+
+// Bump this when a setting is stored differently. A record is only the
+// value, the key already says which setting it is.
+const VERSION: u8 = 1;
+
+impl<L, D, E> Setting<L, D, E> {
+    fn key(&self) -> u8 {
+        key::SETTINGS
+            + match self {
+                Setting::LedMode(_) => 0,
+                Setting::Colour(_, _, _) => 1,
+                Setting::DefaultLayer(_) => 2,
+                Setting::Display(_) => 3,
+                Setting::SleepTimeout(_) => 4,
+                Setting::Brightness(_) => 5,
+                Setting::EncoderMode(_) => 6,
+                Setting::Debounce(_) => 7,
+            }
+    }
+}
+
+// Records from a newer firmware are left alone, as if never set.
+fn decode<L, D, E>(key: u8, version: u8, bytes: &[u8]) -> Option<Setting<L, D, E>>
+where
+    L: DeserializeOwned,
+    D: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    match version {
+        VERSION => match key - key::SETTINGS {
+            0 => from_bytes(bytes).ok().map(Setting::LedMode),
+            1 => from_bytes(bytes)
+                .ok()
+                .map(|(r, g, b)| Setting::Colour(r, g, b)),
+            2 => from_bytes(bytes).ok().map(Setting::DefaultLayer),
+            3 => from_bytes(bytes).ok().map(Setting::Display),
+            4 => from_bytes(bytes).ok().map(Setting::SleepTimeout),
+            5 => from_bytes(bytes).ok().map(Setting::Brightness),
+            6 => from_bytes(bytes).ok().map(Setting::EncoderMode),
+            7 => from_bytes(bytes).ok().map(Setting::Debounce),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn encode<'a, L, D, E>(setting: &Setting<L, D, E>, buffer: &'a mut [u8]) -> Option<&'a mut [u8]>
+where
+    L: Serialize,
+    D: Serialize,
+    E: Serialize,
+{
+    match setting {
+        Setting::LedMode(mode) => to_slice(mode, buffer),
+        Setting::Colour(r, g, b) => to_slice(&(r, g, b), buffer),
+        Setting::DefaultLayer(layer) => to_slice(layer, buffer),
+        Setting::Display(display) => to_slice(display, buffer),
+        Setting::SleepTimeout(minutes) => to_slice(minutes, buffer),
+        Setting::Brightness(brightness) => to_slice(brightness, buffer),
+        Setting::EncoderMode(mode) => to_slice(mode, buffer),
+        Setting::Debounce(config) => to_slice(config, buffer),
+    }
+    .ok()
+}
+
+/// The settings that have been saved, the rest keep their defaults.
+pub fn load<S, L, D, E>(store: &Store<S>) -> impl Iterator<Item = Setting<L, D, E>> + '_
+where
+    S: Storage,
+    L: DeserializeOwned,
+    D: DeserializeOwned,
+    E: DeserializeOwned,
+{
+    (key::SETTINGS..key::SETTINGS + COUNT).filter_map(move |k| {
+        let (version, bytes) = store.get(k)?;
+        decode(k, version, bytes)
+    })
+}
+
+pub fn save<S, L, D, E>(store: &mut Store<S>, setting: Setting<L, D, E>) -> Result<(), store::Error>
+where
+    S: Storage,
+    L: Serialize,
+    D: Serialize,
+    E: Serialize,
+{
+    let mut buffer = [0; 8];
+    let bytes = encode(&setting, &mut buffer).ok_or(store::Error::TooLarge)?;
+    store.set(setting.key(), VERSION, bytes)
+}
+
+/// Forgets every setting, the keymap and macros are kept.
+pub fn reset<S: Storage>(store: &mut Store<S>) -> Result<(), store::Error> {
+    (key::SETTINGS..key::SETTINGS + COUNT).try_for_each(|k| store.remove(k))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::{Config, Strategy};
+    use crate::store::Ram;
+
+    #[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+    enum Mode {
+        Off,
+        On(u8),
+    }
+
+    type Setting = super::Setting<Mode, Mode, Mode>;
+
+    const SETTINGS: [Setting; 8] = [
+        Setting::LedMode(Mode::On(3)),
+        Setting::Colour(1, 2, 3),
+        Setting::DefaultLayer(2),
+        Setting::Display(Mode::Off),
+        Setting::SleepTimeout(10),
+        Setting::Brightness(200),
+        Setting::EncoderMode(Mode::On(1)),
+        Setting::Debounce(Config {
+            strategy: Strategy::Defer,
+            time: 7,
+        }),
+    ];
+
+    fn store() -> Store<Ram> {
+        Store::new(Ram::new(1024))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut store = store();
+        for setting in SETTINGS.iter() {
+            assert!(save(&mut store, *setting).is_ok());
+        }
+        assert!(load(&store).eq(SETTINGS.iter().copied()));
+
+        assert!(reset(&mut store).is_ok());
+        assert_eq!(load::<_, Mode, Mode, Mode>(&store).count(), 0);
+    }
+
+    #[test]
+    fn newer_versions_are_ignored() {
+        let mut store = store();
+        assert!(save(&mut store, SETTINGS[4]).is_ok());
+        assert!(store.set(key::SETTINGS + 5, VERSION + 1, &[1]).is_ok());
+        assert_eq!(
+            load(&store).collect::<std::vec::Vec<Setting>>(),
+            [SETTINGS[4]]
+        );
+    }
+}
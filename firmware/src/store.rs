@@ -0,0 +1,498 @@
+use heapless::{consts::U32, Vec};
+
+/// Two equally sized areas of erasable storage, reading 0xff once erased.
+/// Only the store's layout lives here, so it runs as well against RAM as
+/// against the internal flash.
+pub trait Storage {
+    fn size(&self) -> u32;
+    fn read(&self, area: usize, offset: u32, len: usize) -> &[u8];
+    fn write(&mut self, area: usize, offset: u32, data: &[u8]) -> Result<(), Error>;
+    fn erase(&mut self, area: usize) -> Result<(), Error>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    Storage,
+    Full,
+    TooLarge,
+    // the area to compact into hasn't been erased yet
+    NotErased,
+}
+
+/// What each value in the store is, every module keeps to its own range.
+pub mod key {
+    pub const SETTINGS: u8 = 0x00;
+    pub const KEYMAP: u8 = 0x40;
+    pub const MACROS: u8 = 0x50;
+    // one per layer
+    pub const STATS: u8 = 0x60;
+}
+
+// An area starts with `[sequence number, MAGIC]`, followed by records of
+// `[MARKER, key, version, len]` and `len` bytes of value. The magic and the
+// markers are written last, so anything cut short by a power loss is never
+// read. A record replaces the earlier ones with the same key, version 0
+// removes the key.
+//
+// Records are appended until the area is full, then the latest record of
+// each key is copied over to the other area. That one only takes over once
+// its header is written, until then the old area is still the valid one, so
+// the two areas take turns being erased.
+//
+// Writing never erases, as erasing a flash sector stalls the cpu for a
+// second or more. The other area has to be erased beforehand, with
+// `erase_spare`, while the keyboard isn't being used.
+const MAGIC: [u8; 4] = *b"pkb1";
+const HEADER: u32 = 8;
+const MARKER: u8 = 0xa5;
+const RECORD: u32 = 5;
+const ERASED: u8 = 0xff;
+
+pub struct Store<S> {
+    storage: S,
+    area: usize,
+    sequence: u32,
+    end: u32,
+    // where the latest record of each key starts
+    index: Vec<(u8, u32), U32>,
+    needs_compaction: bool,
+    spare_erased: bool,
+}
+
+impl<S: Storage> Store<S> {
+    pub fn new(storage: S) -> Self {
+        let mut store = Store {
+            storage,
+            area: 0,
+            sequence: 0,
+            end: HEADER,
+            index: Vec::new(),
+            needs_compaction: false,
+            spare_erased: false,
+        };
+
+        // a power loss after compacting leaves both valid, the newer wins
+        match (store.sequence_of(0), store.sequence_of(1)) {
+            (Some(a), Some(b)) if (b.wrapping_sub(a) as i32) > 0 => store.mount(1, b),
+            (Some(a), _) => store.mount(0, a),
+            (None, Some(b)) => store.mount(1, b),
+            // formatted by the first write
+            (None, None) => store.needs_compaction = true,
+        }
+        store.spare_erased = store.is_erased(1 - store.area);
+        store
+    }
+
+    /// Erases the area the next compaction copies to, if it needs it. The
+    /// only call that erases.
+    pub fn erase_spare(&mut self) -> Result<(), Error> {
+        if !self.spare_erased {
+            self.storage.erase(1 - self.area)?;
+            self.spare_erased = true;
+        }
+        Ok(())
+    }
+
+    /// The version and value last set for `key`.
+    pub fn get(&self, key: u8) -> Option<(u8, &[u8])> {
+        let offset = self.offset_of(key)?;
+        let (_, version, len) = self.record_at(self.area, offset)?;
+        match version {
+            0 => None,
+            version => Some((
+                version,
+                self.storage.read(self.area, offset + RECORD, len as usize),
+            )),
+        }
+    }
+
+    /// Nothing is written if `key` already has this value. Versions start
+    /// at 1, 0 marks a removed key.
+    pub fn set(&mut self, key: u8, version: u8, value: &[u8]) -> Result<(), Error> {
+        if self.get(key) == Some((version, value)) {
+            return Ok(());
+        }
+        self.append(key, version, value)
+    }
+
+    pub fn remove(&mut self, key: u8) -> Result<(), Error> {
+        if self.get(key).is_none() {
+            return Ok(());
+        }
+        self.append(key, 0, &[])
+    }
+
+    fn sequence_of(&self, area: usize) -> Option<u32> {
+        let header = self.storage.read(area, 0, HEADER as usize);
+        if header.len() == HEADER as usize && header[4..] == MAGIC {
+            Some(u32::from_le_bytes([
+                header[0], header[1], header[2], header[3],
+            ]))
+        } else {
+            None
+        }
+    }
+
+    fn mount(&mut self, area: usize, sequence: u32) {
+        self.area = area;
+        self.sequence = sequence;
+
+        let mut offset = HEADER;
+        while let Some((key, _, len)) = self.record_at(area, offset) {
+            if offset + RECORD + len > self.storage.size() {
+                break;
+            }
+            self.note(key, offset);
+            offset += RECORD + len;
+        }
+        self.end = offset;
+
+        // anything but erased storage is a record cut short, it's left out
+        // of the copy once the area is compacted
+        let rest = self.storage.read(area, offset, RECORD as usize);
+        self.needs_compaction = rest.iter().any(|b| *b != ERASED);
+    }
+
+    fn is_erased(&self, area: usize) -> bool {
+        let size = self.storage.size() as usize;
+        self.storage
+            .read(area, 0, size)
+            .iter()
+            .all(|b| *b == ERASED)
+    }
+
+    fn record_at(&self, area: usize, offset: u32) -> Option<(u8, u8, u32)> {
+        let header = self.storage.read(area, offset, RECORD as usize);
+        if header.len() < RECORD as usize || header[0] != MARKER {
+            return None;
+        }
+        let len = u16::from_le_bytes([header[3], header[4]]) as u32;
+        Some((header[1], header[2], len))
+    }
+
+    fn offset_of(&self, key: u8) -> Option<u32> {
+        self.index
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, offset)| *offset)
+    }
+
+    fn note(&mut self, key: u8, offset: u32) {
+        match self.index.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = offset,
+            None => {
+                self.index.push((key, offset)).ok();
+            }
+        }
+    }
+
+    fn append(&mut self, key: u8, version: u8, value: &[u8]) -> Result<(), Error> {
+        let size = RECORD + value.len() as u32;
+        if value.len() > u16::MAX as usize || HEADER + size > self.storage.size() {
+            return Err(Error::TooLarge);
+        }
+        if self.offset_of(key).is_none() && self.index.len() == self.index.capacity() {
+            return Err(Error::Full);
+        }
+
+        if self.needs_compaction || self.end + size > self.storage.size() {
+            self.compact()?;
+        }
+        if self.end + size > self.storage.size() {
+            return Err(Error::Full);
+        }
+
+        let len = (value.len() as u16).to_le_bytes();
+        self.storage
+            .write(self.area, self.end + 1, &[key, version, len[0], len[1]])?;
+        self.storage.write(self.area, self.end + RECORD, value)?;
+        self.storage.write(self.area, self.end, &[MARKER])?;
+
+        self.note(key, self.end);
+        self.end += size;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), Error> {
+        let (from, to) = (self.area, 1 - self.area);
+        if !self.spare_erased {
+            return Err(Error::NotErased);
+        }
+        self.spare_erased = false;
+
+        let mut index = Vec::new();
+        let mut end = HEADER;
+        for (key, offset) in self.index.clone().iter() {
+            let len = match self.record_at(from, *offset) {
+                Some((_, 0, _)) | None => continue,
+                Some((_, _, len)) => len,
+            };
+            self.copy(from, *offset, to, end, RECORD + len)?;
+            index.push((*key, end)).ok();
+            end += RECORD + len;
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        self.storage.write(to, 0, &sequence.to_le_bytes())?;
+        self.storage.write(to, 4, &MAGIC)?;
+
+        self.area = to;
+        self.sequence = sequence;
+        self.end = end;
+        self.index = index;
+        self.needs_compaction = false;
+        // only not written to yet if the store was empty
+        self.spare_erased = self.is_erased(from);
+        Ok(())
+    }
+
+    // The marker comes first in a record, it is copied last.
+    fn copy(&mut self, from: usize, src: u32, to: usize, dst: u32, len: u32) -> Result<(), Error> {
+        let mut chunk = [0; 32];
+        let mut done = 1;
+        while done < len {
+            let n = core::cmp::min(chunk.len() as u32, len - done) as usize;
+            chunk[..n].copy_from_slice(self.storage.read(from, src + done, n));
+            self.storage.write(to, dst + done, &chunk[..n])?;
+            done += n as u32;
+        }
+        self.storage.write(to, dst, &[MARKER])
+    }
+}
+
+/// Storage in RAM that behaves like flash: writing only clears bits, and
+/// the power can be cut after a number of bytes.
+#[cfg(test)]
+pub(crate) struct Ram {
+    pub areas: [std::vec::Vec<u8>; 2],
+    // bytes still written before the power is cut
+    pub power: Option<usize>,
+    pub erases: usize,
+}
+
+#[cfg(test)]
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Ram {
+            areas: [std::vec![ERASED; size], std::vec![ERASED; size]],
+            power: None,
+            erases: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Storage for Ram {
+    fn size(&self) -> u32 {
+        self.areas[0].len() as u32
+    }
+
+    fn read(&self, area: usize, offset: u32, len: usize) -> &[u8] {
+        let area = &self.areas[area];
+        let start = core::cmp::min(offset as usize, area.len());
+        let end = core::cmp::min(start + len, area.len());
+        &area[start..end]
+    }
+
+    fn write(&mut self, area: usize, offset: u32, data: &[u8]) -> Result<(), Error> {
+        for (i, byte) in data.iter().enumerate() {
+            match self.power.as_mut() {
+                Some(0) => return Err(Error::Storage),
+                Some(power) => *power -= 1,
+                None => (),
+            }
+            let b = self.areas[area]
+                .get_mut(offset as usize + i)
+                .ok_or(Error::Storage)?;
+            *b &= *byte;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, area: usize) -> Result<(), Error> {
+        if self.power == Some(0) {
+            return Err(Error::Storage);
+        }
+        self.erases += 1;
+        for b in self.areas[area].iter_mut() {
+            *b = ERASED;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a header and a handful of records
+    const SIZE: usize = 64;
+
+    // the power comes back
+    fn restart(store: Store<Ram>) -> Store<Ram> {
+        let mut ram = store.storage;
+        ram.power = None;
+        Store::new(ram)
+    }
+
+    fn header(sequence: u32) -> [u8; HEADER as usize] {
+        let s = sequence.to_le_bytes();
+        [
+            s[0], s[1], s[2], s[3], MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3],
+        ]
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut store = Store::new(Ram::new(SIZE));
+        assert_eq!(store.get(1), None);
+        assert!(store.set(1, 1, b"ab").is_ok());
+        assert!(store.set(2, 3, b"c").is_ok());
+        assert_eq!(store.get(1), Some((1, &b"ab"[..])));
+
+        let mut store = restart(store);
+        assert_eq!(store.get(1), Some((1, &b"ab"[..])));
+        assert_eq!(store.get(2), Some((3, &b"c"[..])));
+        assert!(store.remove(1).is_ok());
+
+        let store = restart(store);
+        assert_eq!(store.get(1), None);
+        assert_eq!(store.get(2), Some((3, &b"c"[..])));
+    }
+
+    #[test]
+    fn unchanged_value_isnt_written() {
+        let mut store = Store::new(Ram::new(SIZE));
+        assert!(store.set(1, 1, b"ab").is_ok());
+        let end = store.end;
+        assert!(store.set(1, 1, b"ab").is_ok());
+        assert_eq!(store.end, end);
+    }
+
+    #[test]
+    fn torn_record() {
+        // the power goes after every byte of the second record in turn
+        for power in 0..RECORD as usize + 2 {
+            let mut store = Store::new(Ram::new(SIZE));
+            assert!(store.set(1, 1, b"ab").is_ok());
+            store.storage.power = Some(power);
+            assert!(store.set(1, 1, b"cd").is_err());
+
+            let mut store = restart(store);
+            assert_eq!(store.get(1), Some((1, &b"ab"[..])), "cut after {}", power);
+
+            // the torn record is left behind by the next write
+            assert!(store.set(1, 1, b"ef").is_ok());
+            let store = restart(store);
+            assert_eq!(store.get(1), Some((1, &b"ef"[..])), "cut after {}", power);
+        }
+    }
+
+    #[test]
+    fn compacts_into_the_other_area() {
+        let mut store = Store::new(Ram::new(SIZE));
+        assert!(store.set(2, 1, b"kept").is_ok());
+        assert_eq!(store.area, 1);
+
+        for n in 0..20u8 {
+            assert!(store.set(1, 1, &[n; 4]).is_ok());
+            if store.area == 0 {
+                break;
+            }
+        }
+        assert_eq!(store.area, 0);
+        assert_eq!(store.get(2), Some((1, &b"kept"[..])));
+
+        // the area left behind is only erased when asked to
+        let erases = store.storage.erases;
+        let last = store.get(1).map(|(_, v)| v[0]).unwrap();
+        let mut full = Ok(());
+        for n in 0..20u8 {
+            full = store.set(1, 1, &[n + 100; 4]);
+            if full.is_err() {
+                break;
+            }
+        }
+        assert_eq!(full, Err(Error::NotErased));
+        assert_eq!(store.storage.erases, erases);
+        assert_eq!(store.area, 0);
+        assert!(store.get(1).map(|(_, v)| v[0]) != Some(last));
+
+        assert!(store.erase_spare().is_ok());
+        assert_eq!(store.storage.erases, erases + 1);
+        assert!(store.set(1, 1, b"back").is_ok());
+        assert_eq!(store.area, 1);
+
+        let store = restart(store);
+        assert_eq!(store.area, 1);
+        assert_eq!(store.get(1), Some((1, &b"back"[..])));
+        assert_eq!(store.get(2), Some((1, &b"kept"[..])));
+    }
+
+    #[test]
+    fn power_cut_while_compacting() {
+        let mut store = Store::new(Ram::new(SIZE));
+        assert!(store.set(2, 1, b"x").is_ok());
+        let mut n = 0;
+        while store.end + RECORD + 4 <= SIZE as u32 {
+            n += 1;
+            assert!(store.set(1, 1, &[n; 4]).is_ok());
+        }
+        let area = store.area;
+
+        // both records are copied, but only half the header that makes the
+        // copy valid
+        store.storage.power = Some((RECORD as usize + 1) + (RECORD as usize + 4) + 4);
+        assert!(store.set(1, 1, b"efgh").is_err());
+
+        let mut store = restart(store);
+        assert_eq!(store.area, area);
+        assert_eq!(store.get(1), Some((1, &[n; 4][..])));
+        assert_eq!(store.get(2), Some((1, &b"x"[..])));
+
+        // the half written copy has to go before compacting again
+        assert_eq!(store.set(1, 1, b"efgh"), Err(Error::NotErased));
+        assert!(store.erase_spare().is_ok());
+        assert!(store.set(1, 1, b"efgh").is_ok());
+        assert_eq!(store.get(1), Some((1, &b"efgh"[..])));
+    }
+
+    #[test]
+    fn both_areas_valid() {
+        // a power cut after compacting, before the old area was erased
+        for (old, new) in [(0, 1), (1, 0)].iter() {
+            let mut ram = Ram::new(SIZE);
+            for (area, sequence, value) in [(*old, 6, b'o'), (*new, 7, b'n')].iter() {
+                ram.write(*area, 0, &header(*sequence)).unwrap();
+                ram.write(*area, HEADER + 1, &[1, 1, 1, 0, *value]).unwrap();
+                ram.write(*area, HEADER, &[MARKER]).unwrap();
+            }
+            let store = Store::new(ram);
+            assert_eq!(store.area, *new);
+            assert_eq!(store.get(1), Some((1, &[b'n'][..])));
+        }
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let mut ram = Ram::new(SIZE);
+        ram.write(0, 0, &header(u32::MAX)).unwrap();
+        let mut store = Store::new(ram);
+        assert_eq!((store.area, store.sequence), (0, u32::MAX));
+
+        for n in 0..20u8 {
+            assert!(store.set(1, 1, &[n; 4]).is_ok());
+            if store.area == 1 {
+                break;
+            }
+        }
+        assert_eq!(store.area, 1);
+        assert_eq!(store.sequence, 0);
+
+        // the old area hasn't been erased yet, 0 still comes after u32::MAX
+        let store = restart(store);
+        assert_eq!((store.area, store.sequence), (1, 0));
+        assert!(store.get(1).is_some());
+    }
+}
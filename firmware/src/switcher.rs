@@ -0,0 +1,68 @@
+use keyberon::key_code::KeyCode;
+
+use crate::keyboard::KbHidReport;
+
+/// Holds `mods` down across presses of the `cycle` keys, e.g. Cmd while
+/// Tab and the arrows move through the app switcher. Any other key, or
+/// `timeout` milliseconds without a cycle key, lets go of them.
+pub struct Switcher {
+    pub mods: &'static [KeyCode],
+    pub cycle: &'static [KeyCode],
+    pub timeout: u16,
+}
+
+#[derive(Default)]
+pub struct Session {
+    switcher: Option<&'static Switcher>,
+    last_active: u32,
+    swallow: Option<u8>,
+}
+
+impl Session {
+    pub fn start(&mut self, switcher: &'static Switcher, now: u32) {
+        self.switcher = Some(switcher);
+        self.last_active = now;
+    }
+
+    pub fn tick(&mut self, now: u32) {
+        if let Some(switcher) = self.switcher {
+            if now.wrapping_sub(self.last_active) > switcher.timeout as u32 {
+                self.switcher = None;
+            }
+        }
+    }
+
+    /// The key that ends the session only lets go of the modifiers, it is
+    /// kept out of the report until it is released.
+    pub fn modify_report(&mut self, now: u32, report: &mut KbHidReport) {
+        if let Some(code) = self.swallow {
+            if report.keys().any(|k| k == code) {
+                report.released(code);
+            } else {
+                self.swallow = None;
+            }
+        }
+
+        let switcher = match self.switcher {
+            Some(switcher) => switcher,
+            None => return,
+        };
+
+        let other = report
+            .keys()
+            .find(|k| !switcher.cycle.iter().any(|c| *c as u8 == *k));
+        if let Some(code) = other {
+            self.switcher = None;
+            self.swallow = Some(code);
+            report.released(code);
+            return;
+        }
+
+        if report.keys().next().is_some() {
+            self.last_active = now;
+        }
+        for kc in switcher.mods.iter() {
+            report.pressed(*kc);
+        }
+    }
+}
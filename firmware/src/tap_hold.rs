@@ -0,0 +1,497 @@
+use heapless::{
+    consts::{U16, U32},
+    spsc::Queue,
+    Vec,
+};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+use serde::{Deserialize, Serialize};
+
+pub type Outputs = Vec<Output, U32>;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Output {
+    Event(Event),
+    TapPress(KeyCode),
+    TapRelease(KeyCode),
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Action {
+    IncrementTappingTerm,
+    DecrementTappingTerm,
+    IncrementQuickTapTerm,
+    DecrementQuickTapTerm,
+    TogglePermissiveHold,
+    ToggleHoldOnOtherKeyPress,
+}
+
+/// Timings are in milliseconds, i.e. scan ticks.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub tapping_term: u16,
+    pub quick_tap_term: u16,
+    pub permissive_hold: bool,
+    pub hold_on_other_key_press: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tapping_term: 200,
+            quick_tap_term: 120,
+            permissive_hold: false,
+            hold_on_other_key_press: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn apply(&mut self, action: Action) {
+        match action {
+            Action::IncrementTappingTerm => {
+                self.tapping_term = core::cmp::min(1000, self.tapping_term + 10)
+            }
+            Action::DecrementTappingTerm => {
+                self.tapping_term = core::cmp::max(50, self.tapping_term.saturating_sub(10))
+            }
+            Action::IncrementQuickTapTerm => {
+                self.quick_tap_term = core::cmp::min(1000, self.quick_tap_term + 10)
+            }
+            Action::DecrementQuickTapTerm => {
+                self.quick_tap_term = self.quick_tap_term.saturating_sub(10)
+            }
+            Action::TogglePermissiveHold => self.permissive_hold = !self.permissive_hold,
+            Action::ToggleHoldOnOtherKeyPress => {
+                self.hold_on_other_key_press = !self.hold_on_other_key_press
+            }
+        }
+    }
+}
+
+/// A key whose action depends on how many times it is tapped in a row,
+/// `taps[n - 1]` is sent after `n` taps. Holding the key goes to the
+/// layout like any other tap-hold key.
+pub struct TapDance {
+    pub taps: &'static [&'static [KeyCode]],
+    pub term: u16,
+}
+
+#[derive(Copy, Clone)]
+pub enum Tap {
+    Key(&'static KeyCode),
+    Dance(&'static TapDance),
+}
+
+impl Tap {
+    fn keys(self, count: u8) -> &'static [KeyCode] {
+        match self {
+            Tap::Key(kc) => core::slice::from_ref(kc),
+            Tap::Dance(dance) => dance
+                .taps
+                .get((count as usize).saturating_sub(1))
+                .or_else(|| dance.taps.last())
+                .copied()
+                .unwrap_or(&[]),
+        }
+    }
+
+    fn max_taps(self) -> u8 {
+        match self {
+            Tap::Key(_) => 1,
+            Tap::Dance(dance) => dance.taps.len() as u8,
+        }
+    }
+
+    fn term(self, config: &Config) -> u16 {
+        match self {
+            Tap::Key(_) => config.tapping_term,
+            Tap::Dance(dance) => dance.term,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Pending {
+    coord: (u8, u8),
+    tap: Tap,
+    since: u32,
+    count: u8,
+    down: bool,
+}
+
+/// Sits in front of the layout and decides whether a tap-hold key was
+/// tapped or held, and how many times a tap dance key was tapped. Until
+/// that is known, every following event is kept back so the layout sees
+/// them in the order they were typed.
+///
+/// A hold forwards the key's own press to the layout, where the hold
+/// action lives. A tap never reaches the layout and is reported as
+/// `TapPress`/`TapRelease` pairs instead.
+pub struct TapHold {
+    config: Config,
+    pending: Option<Pending>,
+    waiting: Queue<Event, U16>,
+    tapped: Option<((u8, u8), &'static [KeyCode])>,
+    release_tap: bool,
+    last_tap: Option<((u8, u8), u32)>,
+}
+
+impl TapHold {
+    pub fn new(config: Config) -> Self {
+        TapHold {
+            config,
+            pending: None,
+            waiting: Queue::new(),
+            tapped: None,
+            release_tap: false,
+            last_tap: None,
+        }
+    }
+
+    pub fn adjust(&mut self, action: Action) {
+        self.config.apply(action);
+    }
+
+    pub fn event<F>(&mut self, now: u32, event: Event, tap_for: F) -> Outputs
+    where
+        F: Fn(u8, u8) -> Option<Tap>,
+    {
+        let mut out = Outputs::new();
+        self.handle(now, event, &tap_for, &mut out);
+        out
+    }
+
+    pub fn tick<F>(&mut self, now: u32, tap_for: F) -> Outputs
+    where
+        F: Fn(u8, u8) -> Option<Tap>,
+    {
+        let mut out = Outputs::new();
+
+        if self.release_tap {
+            self.release_tap = false;
+            self.release_tapped(&mut out);
+            self.flush(now, &tap_for, &mut out);
+        }
+
+        if let Some(pending) = self.pending {
+            if now.wrapping_sub(pending.since) >= pending.tap.term(&self.config) as u32 {
+                if pending.down {
+                    self.hold(pending, &mut out);
+                    self.flush(now, &tap_for, &mut out);
+                } else {
+                    self.tap(now, pending.coord, pending.tap.keys(pending.count), &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.pending.is_some() || self.release_tap
+    }
+
+    fn is_quick_tap(&self, coord: (u8, u8), now: u32) -> bool {
+        match self.last_tap {
+            Some((c, t)) => c == coord && now.wrapping_sub(t) < self.config.quick_tap_term as u32,
+            None => false,
+        }
+    }
+
+    fn handle<F>(&mut self, now: u32, event: Event, tap_for: &F, out: &mut Outputs)
+    where
+        F: Fn(u8, u8) -> Option<Tap>,
+    {
+        if self.is_waiting() {
+            if let Some(pending) = self.pending {
+                match event {
+                    Event::Release(i, j) if (i, j) == pending.coord && pending.down => {
+                        let count = pending.count + 1;
+                        if count >= pending.tap.max_taps() {
+                            self.tap(now, pending.coord, pending.tap.keys(count), out);
+                        } else {
+                            self.pending = Some(Pending {
+                                since: now,
+                                count,
+                                down: false,
+                                ..pending
+                            });
+                        }
+                        return;
+                    }
+                    Event::Press(i, j) if (i, j) == pending.coord && !pending.down => {
+                        self.pending = Some(Pending {
+                            since: now,
+                            down: true,
+                            ..pending
+                        });
+                        return;
+                    }
+                    Event::Press(_, _) if !pending.down => {
+                        self.tap(now, pending.coord, pending.tap.keys(pending.count), out)
+                    }
+                    Event::Press(_, _) if self.config.hold_on_other_key_press => {
+                        self.hold(pending, out)
+                    }
+                    Event::Release(i, j)
+                        if self.config.permissive_hold
+                            && self.waiting.iter().any(|e| *e == Event::Press(i, j)) =>
+                    {
+                        self.hold(pending, out)
+                    }
+                    _ => (),
+                }
+            }
+            if let Err(event) = self.waiting.enqueue(event) {
+                // too much typed to keep waiting, decide now so the queued
+                // events still go out ahead of this one
+                self.resolve(now, out);
+                self.flush(now, tap_for, out);
+                self.handle(now, event, tap_for, out);
+                return;
+            }
+            self.flush(now, tap_for, out);
+            return;
+        }
+
+        match event {
+            Event::Press(i, j) => match tap_for(i, j) {
+                Some(tap @ Tap::Key(_)) if self.is_quick_tap((i, j), now) => {
+                    let keys = tap.keys(1);
+                    self.tapped = Some(((i, j), keys));
+                    for kc in keys {
+                        out.push(Output::TapPress(*kc)).ok();
+                    }
+                }
+                Some(tap) => {
+                    self.pending = Some(Pending {
+                        coord: (i, j),
+                        tap,
+                        since: now,
+                        count: 0,
+                        down: true,
+                    })
+                }
+                None => {
+                    out.push(Output::Event(event)).ok();
+                }
+            },
+            Event::Release(i, j) => match self.tapped {
+                Some((coord, _)) if coord == (i, j) => self.release_tapped(out),
+                _ => {
+                    out.push(Output::Event(event)).ok();
+                }
+            },
+        }
+    }
+
+    fn flush<F>(&mut self, now: u32, tap_for: &F, out: &mut Outputs)
+    where
+        F: Fn(u8, u8) -> Option<Tap>,
+    {
+        while !self.is_waiting() {
+            match self.waiting.dequeue() {
+                Some(event) => self.handle(now, event, tap_for, out),
+                None => break,
+            }
+        }
+    }
+
+    // Settles the pending key as if its term had run out.
+    fn resolve(&mut self, now: u32, out: &mut Outputs) {
+        if let Some(pending) = self.pending {
+            if pending.down {
+                self.hold(pending, out);
+            } else {
+                self.tap(now, pending.coord, pending.tap.keys(pending.count), out);
+            }
+        }
+        if self.release_tap {
+            self.release_tap = false;
+            self.release_tapped(out);
+        }
+    }
+
+    fn release_tapped(&mut self, out: &mut Outputs) {
+        if let Some((_, keys)) = self.tapped.take() {
+            for kc in keys {
+                out.push(Output::TapRelease(*kc)).ok();
+            }
+        }
+    }
+
+    fn tap(&mut self, now: u32, coord: (u8, u8), keys: &'static [KeyCode], out: &mut Outputs) {
+        self.release_tapped(out);
+        self.pending = None;
+        self.tapped = Some((coord, keys));
+        self.release_tap = true;
+        self.last_tap = Some((coord, now));
+        for kc in keys {
+            out.push(Output::TapPress(*kc)).ok();
+        }
+    }
+
+    fn hold(&mut self, pending: Pending, out: &mut Outputs) {
+        self.pending = None;
+        self.last_tap = None;
+        out.push(Output::Event(Event::Press(pending.coord.0, pending.coord.1)))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Event::{Press, Release};
+    use Output::{TapPress, TapRelease};
+
+    const KEY: (u8, u8) = (0, 0);
+
+    fn tap_for(i: u8, j: u8) -> Option<Tap> {
+        if (i, j) == KEY {
+            Some(Tap::Key(&KeyCode::A))
+        } else {
+            None
+        }
+    }
+
+    fn tap_hold(permissive_hold: bool) -> TapHold {
+        TapHold::new(Config {
+            permissive_hold,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn tap() {
+        let mut th = tap_hold(false);
+        assert_eq!(&th.event(0, Press(0, 0), tap_for)[..], &[]);
+        assert_eq!(&th.tick(100, tap_for)[..], &[]);
+        assert_eq!(
+            &th.event(150, Release(0, 0), tap_for)[..],
+            &[TapPress(KeyCode::A)]
+        );
+        assert_eq!(&th.tick(151, tap_for)[..], &[TapRelease(KeyCode::A)]);
+    }
+
+    #[test]
+    fn hold() {
+        let mut th = tap_hold(false);
+        th.event(0, Press(0, 0), tap_for);
+        assert_eq!(&th.tick(199, tap_for)[..], &[]);
+        assert_eq!(&th.tick(200, tap_for)[..], &[Output::Event(Press(0, 0))]);
+        assert_eq!(
+            &th.event(300, Release(0, 0), tap_for)[..],
+            &[Output::Event(Release(0, 0))]
+        );
+    }
+
+    #[test]
+    fn permissive_hold() {
+        // another key tapped inside the tap-hold key's press
+        let roll = |th: &mut TapHold| {
+            let mut out = std::vec::Vec::new();
+            for (now, event) in [
+                (0, Press(0, 0)),
+                (50, Press(1, 1)),
+                (80, Release(1, 1)),
+                (100, Release(0, 0)),
+            ]
+            .iter()
+            {
+                out.extend_from_slice(&th.event(*now, *event, tap_for));
+            }
+            out.extend_from_slice(&th.tick(101, tap_for));
+            out
+        };
+
+        assert_eq!(
+            roll(&mut tap_hold(true)),
+            [
+                Output::Event(Press(0, 0)),
+                Output::Event(Press(1, 1)),
+                Output::Event(Release(1, 1)),
+                Output::Event(Release(0, 0)),
+            ]
+        );
+        assert_eq!(
+            roll(&mut tap_hold(false)),
+            [
+                TapPress(KeyCode::A),
+                TapRelease(KeyCode::A),
+                Output::Event(Press(1, 1)),
+                Output::Event(Release(1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn quick_tap() {
+        let mut th = tap_hold(false);
+        th.event(0, Press(0, 0), tap_for);
+        th.event(50, Release(0, 0), tap_for);
+        th.tick(51, tap_for);
+
+        // pressed again straight away, it repeats the tap instead of holding
+        assert_eq!(
+            &th.event(100, Press(0, 0), tap_for)[..],
+            &[TapPress(KeyCode::A)]
+        );
+        assert_eq!(&th.tick(400, tap_for)[..], &[]);
+        assert_eq!(
+            &th.event(500, Release(0, 0), tap_for)[..],
+            &[TapRelease(KeyCode::A)]
+        );
+
+        // after the quick tap term, it can be held again
+        th.event(1000, Press(0, 0), tap_for);
+        assert_eq!(&th.tick(1200, tap_for)[..], &[Output::Event(Press(0, 0))]);
+    }
+
+    #[test]
+    fn timeout_mid_roll() {
+        let mut th = tap_hold(false);
+        th.event(0, Press(0, 0), tap_for);
+        assert_eq!(&th.event(150, Press(1, 1), tap_for)[..], &[]);
+        assert_eq!(
+            &th.tick(200, tap_for)[..],
+            &[Output::Event(Press(0, 0)), Output::Event(Press(1, 1))]
+        );
+        assert_eq!(
+            &th.event(210, Release(0, 0), tap_for)[..],
+            &[Output::Event(Release(0, 0))]
+        );
+        assert_eq!(
+            &th.event(220, Release(1, 1), tap_for)[..],
+            &[Output::Event(Release(1, 1))]
+        );
+    }
+
+    #[test]
+    fn overflow_resolves_pending_first() {
+        let mut th = tap_hold(false);
+        th.event(0, Press(0, 0), tap_for);
+
+        // the queue holds 16 events, the 17th can't wait
+        let events = (0..18).map(|n| {
+            if n % 2 == 0 {
+                Press(1, n / 2)
+            } else {
+                Release(1, n / 2)
+            }
+        });
+        let mut out = std::vec::Vec::new();
+        for event in events.clone() {
+            out.extend_from_slice(&th.event(10, event, tap_for));
+        }
+
+        let mut expected = std::vec![Output::Event(Press(0, 0))];
+        expected.extend(events.map(Output::Event));
+        assert_eq!(out, expected);
+        assert_eq!(
+            &th.event(20, Release(0, 0), tap_for)[..],
+            &[Output::Event(Release(0, 0))]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use heapless::{
+    consts::{U4, U64},
+    spsc::Queue,
+    Vec,
+};
+use keyberon::key_code::KeyCode;
+
+use crate::host_layout::HostLayout;
+
+pub type Chord = Vec<KeyCode, U4>;
+
+/// Types queued chords, one report at a time, on top of whatever the
+/// layout reports. Each chord is held for `interval` ticks and released
+/// for as long again. Modifiers shared with the next chord stay held in
+/// between, so sequences like macOS unicode input keep their Option.
+///
+/// Strings are turned into chords a character at a time, as the queue
+/// runs dry, so they can be any length.
+pub struct Typing {
+    queue: Queue<Chord, U64>,
+    strings: Queue<&'static str, U4>,
+    text: &'static str,
+    layout: HostLayout,
+    current: Chord,
+    pressed: bool,
+    ticks: u16,
+    interval: u16,
+}
+
+impl Typing {
+    pub fn new(interval: u16, layout: HostLayout) -> Self {
+        Typing {
+            queue: Queue::new(),
+            strings: Queue::new(),
+            text: "",
+            layout,
+            current: Chord::new(),
+            pressed: false,
+            ticks: 0,
+            interval,
+        }
+    }
+
+    pub fn type_str(&mut self, s: &'static str) {
+        self.strings.enqueue(s).ok();
+    }
+
+    pub fn type_chord(&mut self, keys: &[KeyCode]) {
+        if let Ok(chord) = Chord::from_slice(keys) {
+            self.queue.enqueue(chord).ok();
+        }
+    }
+
+    /// Types `c` with the macOS "Unicode Hex Input" source, i.e. the utf-16
+    /// code units in hex while Option is held.
+    pub fn type_unicode(&mut self, c: char) {
+        let mut units = [0; 2];
+        for unit in c.encode_utf16(&mut units).iter() {
+            for shift in [12, 8, 4, 0].iter() {
+                self.type_chord(&[KeyCode::LAlt, hex_key((unit >> shift) as u8 & 0xf)]);
+            }
+        }
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.pressed || !self.queue.is_empty() || !self.text.is_empty() || !self.strings.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &KeyCode> {
+        self.current.iter()
+    }
+
+    pub fn tick(&mut self) {
+        if self.ticks > 0 {
+            self.ticks -= 1;
+            return;
+        }
+
+        if self.queue.is_empty() {
+            self.next_char();
+        }
+
+        if self.pressed {
+            let mut release = Chord::new();
+            if let Some(next) = self.queue.peek() {
+                for kc in self.current.iter() {
+                    if kc.is_modifier() && next.contains(kc) {
+                        release.push(*kc).ok();
+                    }
+                }
+            }
+            self.current = release;
+            self.pressed = false;
+        } else if let Some(next) = self.queue.dequeue() {
+            self.current = next;
+            self.pressed = true;
+        } else {
+            self.current.clear();
+            return;
+        }
+        self.ticks = self.interval;
+    }
+
+    fn next_char(&mut self) {
+        if self.text.is_empty() {
+            self.text = self.strings.dequeue().unwrap_or("");
+        }
+
+        while let Some(c) = self.text.chars().next() {
+            self.text = &self.text[c.len_utf8()..];
+            match self.layout.key_for(c) {
+                Some((kc, true)) => self.type_chord(&[KeyCode::LShift, kc]),
+                Some((kc, false)) => self.type_chord(&[kc]),
+                // nothing types it on this layout
+                None => continue,
+            }
+            return;
+        }
+    }
+}
+
+fn hex_key(digit: u8) -> KeyCode {
+    use KeyCode::*;
+    match digit {
+        0 => Kb0,
+        1 => Kb1,
+        2 => Kb2,
+        3 => Kb3,
+        4 => Kb4,
+        5 => Kb5,
+        6 => Kb6,
+        7 => Kb7,
+        8 => Kb8,
+        9 => Kb9,
+        10 => A,
+        11 => B,
+        12 => C,
+        13 => D,
+        14 => E,
+        _ => F,
+    }
+}
@@ -0,0 +1,57 @@
+// Display updates, 24 a second.
+const BUCKET_TICKS: u32 = 12;
+const BUCKETS: usize = 10;
+// presses to a word
+const WORD: u32 = 5;
+// a window's worth of presses to words per minute
+const PER_MINUTE: u32 = 60 * 24 / (BUCKETS as u32 * BUCKET_TICKS);
+
+/// Words per minute over the last five seconds of key presses, taken in
+/// half second buckets.
+pub struct Wpm {
+    buckets: [u16; BUCKETS],
+    // the one presses are counted into
+    bucket: usize,
+    ticks: u32,
+    wpm: u8,
+}
+
+impl Default for Wpm {
+    fn default() -> Self {
+        Wpm {
+            buckets: [0; BUCKETS],
+            bucket: 0,
+            ticks: 0,
+            wpm: 0,
+        }
+    }
+}
+
+impl Wpm {
+    pub fn press(&mut self) {
+        self.buckets[self.bucket] = self.buckets[self.bucket].saturating_add(1);
+    }
+
+    /// Called every display update, the estimate when a bucket fills up
+    /// and it has changed.
+    pub fn tick(&mut self) -> Option<u8> {
+        self.ticks += 1;
+        if self.ticks < BUCKET_TICKS {
+            return None;
+        }
+        self.ticks = 0;
+
+        let presses: u32 = self.buckets.iter().map(|p| *p as u32).sum();
+        let wpm = (presses * PER_MINUTE / WORD).min(u8::MAX as u32) as u8;
+
+        self.bucket = (self.bucket + 1) % BUCKETS;
+        self.buckets[self.bucket] = 0;
+
+        if wpm != self.wpm {
+            self.wpm = wpm;
+            Some(wpm)
+        } else {
+            None
+        }
+    }
+}